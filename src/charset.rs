@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+
+/// Built in translations from characters to the codes a machine displays them with
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    /// Characters up to `$FF` are stored as their own code
+    Ascii,
+    /// Commodore PETSCII, lowercase letters use the unshifted codes
+    Petscii,
+    /// Commodore screen codes, as stored directly in screen memory
+    Screen,
+    /// Atari ATASCII
+    Atascii,
+}
+
+impl Encoding {
+    /// Every encoding in the order of `NAMES`
+    const ALL: [Encoding; 4] = [Encoding::Ascii, Encoding::Petscii, Encoding::Screen, Encoding::Atascii];
+
+    /// Names used to select an encoding with `.CHARSET`
+    pub const NAMES: [&'static str; 4] = ["ASCII", "PETSCII", "SCREEN", "ATASCII"];
+
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        Encoding::NAMES.iter().position(|n| *n == name).map(|i| Encoding::ALL[i])
+    }
+
+    pub fn name(self) -> &'static str {
+        Encoding::NAMES[self as usize]
+    }
+
+    /// Converts a character, control characters are passed through unchanged.
+    /// Bytes written with `\xNN` never get here since they are stored as they are.
+    fn encode(self, c: char) -> Option<u8> {
+        let code = c as u32;
+        let passed_through = code < 0x20;
+
+        match (self, c) {
+            (Encoding::Ascii, _) if code <= 0xff => Some(code as u8),
+
+            (Encoding::Petscii, '\n') => Some(0x0d),
+            (Encoding::Petscii, ' '..='@') | (Encoding::Petscii, '[') | (Encoding::Petscii, ']') => Some(code as u8),
+            (Encoding::Petscii, 'a'..='z') => Some(code as u8 - 0x20),
+            (Encoding::Petscii, 'A'..='Z') => Some(code as u8 + 0x80),
+            (Encoding::Petscii, '£') => Some(0x5c),
+            (Encoding::Petscii, '↑') => Some(0x5e),
+            (Encoding::Petscii, '←') => Some(0x5f),
+            (Encoding::Petscii, 'π') => Some(0xff),
+
+            (Encoding::Screen, '@') => Some(0x00),
+            (Encoding::Screen, 'a'..='z') => Some(code as u8 - 0x60),
+            (Encoding::Screen, '[') => Some(0x1b),
+            (Encoding::Screen, '£') => Some(0x1c),
+            (Encoding::Screen, ']') => Some(0x1d),
+            (Encoding::Screen, '↑') => Some(0x1e),
+            (Encoding::Screen, '←') => Some(0x1f),
+            (Encoding::Screen, ' '..='?') | (Encoding::Screen, 'A'..='Z') => Some(code as u8),
+            (Encoding::Screen, 'π') => Some(0x5e),
+
+            (Encoding::Atascii, '\n') => Some(0x9b),
+            (Encoding::Atascii, ' '..='_') | (Encoding::Atascii, 'a'..='z') | (Encoding::Atascii, '|') => Some(code as u8),
+
+            (Encoding::Ascii, _) => None,
+            _ if passed_through => Some(code as u8),
+            _ => None,
+        }
+    }
+}
+
+/// The encoding in use along with any characters remapped by `.CHARMAP`
+pub struct Charset {
+    pub encoding: Encoding,
+    remapped: HashMap<char, u8>,
+}
+
+impl Charset {
+    pub fn new(encoding: Encoding) -> Charset {
+        Charset {
+            encoding,
+            remapped: HashMap::new(),
+        }
+    }
+
+    pub fn remap(&mut self, c: char, code: u8) {
+        self.remapped.insert(c, code);
+    }
+
+    pub fn encode(&self, c: char) -> Option<u8> {
+        self.remapped.get(&c).copied().or_else(|| self.encoding.encode(c))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(encoding: Encoding, text: &str) -> Vec<Option<u8>> {
+        text.chars().map(|c| encoding.encode(c)).collect()
+    }
+
+    #[test]
+    fn ascii_keeps_codes_up_to_ff() {
+        assert_eq!(encode(Encoding::Ascii, "Az\n\u{e9}"), vec![Some(0x41), Some(0x7a), Some(0x0a), Some(0xe9)]);
+        assert_eq!(Encoding::Ascii.encode('π'), None);
+    }
+
+    #[test]
+    fn petscii_swaps_cases() {
+        assert_eq!(encode(Encoding::Petscii, "aA1\n"), vec![Some(0x41), Some(0xc1), Some(0x31), Some(0x0d)]);
+        assert_eq!(encode(Encoding::Petscii, "£π{"), vec![Some(0x5c), Some(0xff), None]);
+    }
+
+    #[test]
+    fn screen_codes_start_at_at_sign() {
+        assert_eq!(encode(Encoding::Screen, "@a A"), vec![Some(0x00), Some(0x01), Some(0x20), Some(0x41)]);
+        assert_eq!(Encoding::Screen.encode('`'), None);
+    }
+
+    #[test]
+    fn atascii_ends_lines_with_9b() {
+        assert_eq!(encode(Encoding::Atascii, "Aa\n"), vec![Some(0x41), Some(0x61), Some(0x9b)]);
+        assert_eq!(Encoding::Atascii.encode('~'), None);
+    }
+
+    #[test]
+    fn control_codes_pass_through() {
+        assert_eq!(Encoding::Petscii.encode('\u{7}'), Some(0x07));
+        assert_eq!(Encoding::Screen.encode('\u{a0}'), None);
+    }
+
+    #[test]
+    fn remapped_characters_take_precedence() {
+        let mut charset = Charset::new(Encoding::Petscii);
+        charset.remap('a', 0x01);
+        assert_eq!(charset.encode('a'), Some(0x01));
+        assert_eq!(charset.encode('b'), Some(0x42));
+    }
+
+    #[test]
+    fn names_select_encodings() {
+        assert_eq!(Encoding::from_name("SCREEN"), Some(Encoding::Screen));
+        assert_eq!(Encoding::from_name("EBCDIC"), None);
+        assert_eq!(Encoding::Atascii.name(), "ATASCII");
+    }
+}
@@ -0,0 +1,522 @@
+use std::fmt;
+
+use crate::charset::Encoding;
+use crate::expr::ExprError;
+use crate::lexer::Span;
+use crate::parser::DIRECTIVES;
+use crate::{Addressing, OPS};
+
+/// A source file kept around so diagnostics can quote the offending line
+pub struct SourceFile {
+    pub name: String,
+    pub code: String,
+}
+
+/// Lines assembled somewhere other than where they were written, which the
+/// spans of those lines refer back to
+pub enum Expansion {
+    /// A use of a macro
+    Macro { name: String, call: Span },
+    /// One pass through a `.REPEAT` block, counting from zero
+    Repeat { iteration: i64, call: Span },
+}
+
+impl Expansion {
+    /// The line that caused the expansion
+    pub fn call(&self) -> Span {
+        match self {
+            Expansion::Macro { call, .. } | Expansion::Repeat { call, .. } => *call,
+        }
+    }
+}
+
+/// The files read so far and the macro expansions made from them
+pub struct Sources {
+    pub files: Vec<SourceFile>,
+    pub expansions: Vec<Expansion>,
+}
+
+/// Everything that can go wrong while assembling, each pointing at the part
+/// of the source responsible.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AssemblyError {
+    /// Tokens that do not form a valid statement
+    Syntax { message: String, span: Span },
+    /// A number literal with digits that do not match its prefix
+    BadNumber { text: String, span: Span },
+    UnknownMnemonic { name: String, span: Span },
+    UnknownDirective { name: String, span: Span },
+    UnknownCharset { name: String, span: Span },
+    /// A character literal the current character set has no code for
+    UnmappedChar { c: char, charset: &'static str, span: Span },
+    /// The operand is written in a way the opcode has no encoding for
+    InvalidAddressingMode { mnemonic: String, mode: Addressing, span: Span },
+    /// An opcode written without an operand that it needs
+    MissingOperand { mnemonic: String, span: Span },
+    /// A `.B` or `.W` suffix asking for an operand size the opcode can not use
+    ForcedSize { mnemonic: String, mode: Addressing, bytes: u16, span: Span },
+    /// A symbol that was never declared, with the closest declared name
+    UndefinedSymbol { name: String, suggestion: Option<String>, span: Span },
+    /// A define whose value depends on itself
+    CircularDefinition { name: String, span: Span },
+    DivideByZero { span: Span },
+    /// Arithmetic whose result does not fit in 64 bits
+    Overflow { span: Span },
+    /// A value that does not fit in the bytes available for it
+    ValueOutOfRange { value: i64, bytes: usize, span: Span },
+    /// A negative number of bytes to skip, or one that runs past the end of memory
+    BadSize { count: i64, pc: u16, span: Span },
+    /// Code or data that runs past `$FFFF` when there is no layout
+    PastEndOfMemory { pc: u32, span: Span },
+    /// An `.ALIGN` boundary that is not between 1 and `$10000`
+    BadAlignment { boundary: i64, span: Span },
+    /// A `.RES` or `.ALIGN` whose size, or an `ORG` whose address, changes every
+    /// time the labels it depends on move, so there is no address to give the
+    /// lines after it
+    Unsettled { directive: String, span: Span },
+    /// A branch whose target is more than 128 bytes away
+    BranchOutOfRange { distance: i64, span: Span },
+    /// A file that is not next to the file referring to it or in any include directory
+    FileNotFound { path: String, span: Span },
+    /// A file that was found but could not be read
+    FileError { path: String, message: String, span: Span },
+    /// An `.INCBIN` offset and length that do not fit in the file
+    BinaryRange { path: String, start: i64, end: i64, size: usize, span: Span },
+    /// A file that includes itself, directly or through other files
+    CircularInclude { path: String, span: Span },
+    /// A directive that closes or continues a block that was never opened
+    Unmatched { directive: String, opener: &'static str, span: Span },
+    /// A block still open at the end of the file it was opened in
+    Unterminated { directive: &'static str, closer: &'static str, span: Span },
+    /// A `.REPEAT` count that is negative or more than `$10000`
+    BadRepeat { count: i64, span: Span },
+    /// A macro that uses itself, directly or through other macros
+    RecursiveMacro { name: String, span: Span },
+    /// Arguments that do not match the parameters of a macro
+    BadArguments { message: String, span: Span, definition: Span },
+    /// A segment that is not in the layout, along with those that are
+    UnknownSegment { name: String, segments: Vec<String>, span: Span },
+    /// A segment with more in it than fits in its area, pointing at the
+    /// first line that does not fit
+    SegmentOverflow { name: String, start: u16, size: u32, by: u32, span: Span },
+    /// Code or data before the first `.SEGMENT` when the layout has no `CODE`
+    /// segment for it to go in
+    NoSegment { span: Span },
+    /// Code or data in a segment that is not output, where space can only be reserved
+    NotOutput { segment: String, span: Span },
+    /// A `.ASSERT` whose condition is false once every label has its address
+    AssertionFailed { message: Option<String>, span: Span },
+    /// A directive that needs to know an address, which object files only get
+    /// once they are linked
+    NotRelocatable { directive: &'static str, span: Span },
+    /// An `ORG` when a layout is given, which already says where each segment goes
+    OriginInLayout { span: Span },
+    /// A `.ERROR` in code that is being assembled
+    UserError { message: String, span: Span },
+    /// A `.WARNING` in code that is being assembled, the only diagnostic that
+    /// does not stop the program from being assembled
+    UserWarning { message: String, span: Span },
+    /// A label, define or macro declared more than once
+    DuplicateSymbol { name: String, span: Span, previous: Span },
+}
+
+impl AssemblyError {
+    /// Attaches the span of the expression that failed to evaluate
+    pub fn from_expr(error: ExprError, span: Span) -> AssemblyError {
+        match error {
+            ExprError::Undefined(name) => AssemblyError::UndefinedSymbol { name, suggestion: None, span },
+            ExprError::Circular(name) => AssemblyError::CircularDefinition { name, span },
+            ExprError::DivideByZero => AssemblyError::DivideByZero { span },
+            ExprError::Overflow => AssemblyError::Overflow { span },
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            AssemblyError::Syntax { span, .. }
+            | AssemblyError::BadNumber { span, .. }
+            | AssemblyError::UnknownMnemonic { span, .. }
+            | AssemblyError::UnknownDirective { span, .. }
+            | AssemblyError::UnknownCharset { span, .. }
+            | AssemblyError::UnmappedChar { span, .. }
+            | AssemblyError::InvalidAddressingMode { span, .. }
+            | AssemblyError::MissingOperand { span, .. }
+            | AssemblyError::ForcedSize { span, .. }
+            | AssemblyError::UndefinedSymbol { span, .. }
+            | AssemblyError::CircularDefinition { span, .. }
+            | AssemblyError::DivideByZero { span }
+            | AssemblyError::Overflow { span }
+            | AssemblyError::ValueOutOfRange { span, .. }
+            | AssemblyError::BadSize { span, .. }
+            | AssemblyError::PastEndOfMemory { span, .. }
+            | AssemblyError::BadAlignment { span, .. }
+            | AssemblyError::Unsettled { span, .. }
+            | AssemblyError::BranchOutOfRange { span, .. }
+            | AssemblyError::FileNotFound { span, .. }
+            | AssemblyError::FileError { span, .. }
+            | AssemblyError::BinaryRange { span, .. }
+            | AssemblyError::CircularInclude { span, .. }
+            | AssemblyError::Unmatched { span, .. }
+            | AssemblyError::Unterminated { span, .. }
+            | AssemblyError::BadRepeat { span, .. }
+            | AssemblyError::RecursiveMacro { span, .. }
+            | AssemblyError::BadArguments { span, .. }
+            | AssemblyError::UnknownSegment { span, .. }
+            | AssemblyError::SegmentOverflow { span, .. }
+            | AssemblyError::NoSegment { span }
+            | AssemblyError::NotOutput { span, .. }
+            | AssemblyError::AssertionFailed { span, .. }
+            | AssemblyError::NotRelocatable { span, .. }
+            | AssemblyError::OriginInLayout { span }
+            | AssemblyError::UserError { span, .. }
+            | AssemblyError::UserWarning { span, .. }
+            | AssemblyError::DuplicateSymbol { span, .. } => *span,
+        }
+    }
+
+    pub fn is_warning(&self) -> bool {
+        matches!(self, AssemblyError::UserWarning { .. })
+    }
+
+    /// Extra information shown below the source excerpt
+    fn notes(&self) -> Vec<(&'static str, String, Option<Span>)> {
+        match self {
+            AssemblyError::UnknownMnemonic { name, .. } => closest(name, OPS.iter().copied())
+                .map(|op| vec![("help", format!("did you mean `{}`?", op), None)])
+                .unwrap_or_default(),
+            AssemblyError::UnknownDirective { name, .. } => closest(name, DIRECTIVES.iter().copied())
+                .map(|directive| vec![("help", format!("did you mean `{}`?", directive), None)])
+                .unwrap_or_default(),
+            AssemblyError::UnknownCharset { .. } => {
+                vec![("note", format!("the character sets are: {}", Encoding::NAMES.join(", ")), None)]
+            }
+            AssemblyError::UnmappedChar { .. } => {
+                vec![("help", "use `.CHARMAP` to give it a code".to_string(), None)]
+            }
+            AssemblyError::InvalidAddressingMode { mnemonic, .. }
+            | AssemblyError::MissingOperand { mnemonic, .. }
+            | AssemblyError::ForcedSize { mnemonic, .. } => {
+                let modes: Vec<&str> = OPS.iter().position(|op| op == mnemonic)
+                    .map(|op| Addressing::ALL.iter().filter(|mode| mode.supported_by(op)).map(|mode| mode.name()).collect())
+                    .unwrap_or_default();
+                vec![("note", format!("`{}` supports: {}", mnemonic, modes.join(", ")), None)]
+            }
+            AssemblyError::UndefinedSymbol { suggestion: Some(suggestion), .. } => {
+                vec![("help", format!("did you mean `{}`?", suggestion), None)]
+            }
+            AssemblyError::ValueOutOfRange { bytes: 1, .. } => {
+                vec![("help", "use `<` or `>` to take the low or high byte".to_string(), None)]
+            }
+            AssemblyError::BranchOutOfRange { distance, .. } => vec![
+                ("note", format!("the target is {} bytes away but branches reach -128 to 127", distance), None),
+                ("help", "branch the opposite way over a `JMP` to the target".to_string(), None),
+            ],
+            AssemblyError::Unsettled { directive, .. } if directive == "ORG" => {
+                vec![("help", "make the address depend only on labels placed before it".to_string(), None)]
+            }
+            AssemblyError::Unsettled { .. } => {
+                vec![("help", "make the size depend only on labels placed before it".to_string(), None)]
+            }
+            AssemblyError::FileNotFound { .. } => {
+                vec![("help", "add the directory it is in with `-I`".to_string(), None)]
+            }
+            AssemblyError::Unterminated { closer, .. } => {
+                vec![("help", format!("add `{}` where the block should end", closer), None)]
+            }
+            AssemblyError::UnknownSegment { name, segments, .. } if segments.is_empty() => {
+                vec![("note", format!("there is no layout to put `{}` in, give one with `--layout`", name), None)]
+            }
+            AssemblyError::UnknownSegment { name, segments, .. } => {
+                match closest(name, segments.iter().map(String::as_str)) {
+                    Some(segment) => vec![("help", format!("did you mean `{}`?", segment), None)],
+                    None => vec![("note", format!("the segments are: {}", segments.join(", ")), None)],
+                }
+            }
+            AssemblyError::SegmentOverflow { name, start, size, .. } => vec![(
+                "note",
+                format!("`{}` has ${:X} bytes from ${:04X} to ${:04X}", name, size, start, *start as u32 + size - 1),
+                None,
+            )],
+            AssemblyError::NoSegment { .. } => {
+                vec![("help", "add a `.SEGMENT` above it, or a `CODE` segment to the layout".to_string(), None)]
+            }
+            AssemblyError::NotOutput { .. } => {
+                vec![("help", "use `.RES` without a fill value to reserve space".to_string(), None)]
+            }
+            AssemblyError::NotRelocatable { .. } => {
+                vec![("help", "use `.SEGMENT` and let the layout given to `link` place the code".to_string(), None)]
+            }
+            AssemblyError::OriginInLayout { .. } => {
+                vec![("help", "add a segment to the layout for the code and switch to it with `.SEGMENT`".to_string(), None)]
+            }
+            AssemblyError::BadArguments { definition, .. } => {
+                vec![("note", "the macro is defined here".to_string(), Some(*definition))]
+            }
+            AssemblyError::DuplicateSymbol { previous, .. } => {
+                vec![("note", "previously defined here".to_string(), Some(*previous))]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Formats the error with the line of source it points to, followed by
+    /// each use of a macro that led to that line
+    pub fn render(&self, sources: &Sources, color: bool) -> String {
+        let level = if self.is_warning() { "warning" } else { "error" };
+        let mut s = render(level, &self.to_string(), self.span(), sources, color);
+        // Notes without a span line up with the `|` after the line number
+        let indent = " ".repeat(self.span().line.to_string().len() + 1);
+        for (level, message, span) in self.notes() {
+            match span {
+                Some(span) => s.push_str(&render(level, &message, span, sources, color)),
+                None => s.push_str(&format!("{}{} {}: {}\n", indent, paint("=", BLUE, color), paint(level, BOLD, color), message)),
+            }
+        }
+
+        let mut expansion = self.span().expansion;
+        while let Some(used) = expansion.checked_sub(1).and_then(|index| sources.expansions.get(index)) {
+            let message = match used {
+                Expansion::Macro { name, .. } => format!("in this use of macro `{}`", name),
+                Expansion::Repeat { iteration, .. } => format!("in this `.REPEAT` with the counter at {}", iteration),
+            };
+            s.push_str(&render("note", &message, used.call(), sources, color));
+            expansion = used.call().expansion;
+        }
+        s
+    }
+}
+
+/// Finds the name most similar to `name`, as long as it is a plausible typo.
+/// Differences in case are not counted so `loop` can suggest `LOOP`.
+pub fn closest<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let upper = name.to_uppercase();
+    candidates
+        .map(|candidate| {
+            let distance = strsim::levenshtein(&upper, &candidate.to_uppercase());
+            (distance, strsim::levenshtein(name, candidate), candidate)
+        })
+        .filter(|&(distance, _, _)| distance <= (name.len() / 3).max(1))
+        .min()
+        .map(|(_, _, candidate)| candidate)
+}
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+
+/// Wraps text in an ANSI colour when colour is enabled
+fn paint(text: &str, style: &str, color: bool) -> String {
+    if color {
+        format!("{}{}\x1b[0m", style, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Formats a message in the style of rustc, quoting the source line with the
+/// span underlined:
+///
+/// ```text
+/// error: unknown opcode `LDZ`
+///  --> prog.asm:1:8
+///   |
+/// 1 | START: LDZ #1
+///   |        ^^^
+/// ```
+fn render(level: &str, message: &str, span: Span, sources: &Sources, color: bool) -> String {
+    let level_style = match level {
+        "error" => RED,
+        "warning" => YELLOW,
+        _ => BOLD,
+    };
+    let mut s = format!("{}{}\n", paint(level, level_style, color), paint(&format!(": {}", message), BOLD, color));
+
+    let source = match sources.files.get(span.file) {
+        Some(source) => source,
+        None => return s,
+    };
+    let line_number = span.line.to_string();
+    let gutter = " ".repeat(line_number.len());
+    s.push_str(&format!("{}{} {}:{}:{}\n", gutter, paint("-->", BLUE, color), source.name, span.line, span.column));
+
+    if let Some(line) = source.code.lines().nth(span.line - 1) {
+        // Tabs are expanded so the underline lines up with the text above it
+        let expand = |c: char| if c == '\t' { "    ".to_string() } else { c.to_string() };
+        let text: String = line.chars().map(expand).collect();
+        let offset: usize = line.chars().take(span.column - 1).map(|c| expand(c).chars().count()).sum();
+        let width: usize = line.chars().skip(span.column - 1).take(span.len).map(|c| expand(c).chars().count()).sum();
+
+        let bar = paint("|", BLUE, color);
+        s.push_str(&format!("{} {}\n", gutter, bar));
+        s.push_str(&format!("{} {} {}\n", paint(&line_number, BLUE, color), bar, text));
+        s.push_str(&format!("{} {} {}{}\n", gutter, bar, " ".repeat(offset), paint(&"^".repeat(width.max(1)), level_style, color)));
+    }
+    s
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssemblyError::Syntax { message, .. } => write!(f, "{}", message),
+            AssemblyError::BadNumber { text, .. } => write!(f, "invalid number `{}`", text),
+            AssemblyError::UnknownMnemonic { name, .. } => write!(f, "unknown opcode `{}`", name),
+            AssemblyError::UnknownDirective { name, .. } => write!(f, "unknown directive `{}`", name),
+            AssemblyError::UnknownCharset { name, .. } => write!(f, "unknown character set `{}`", name),
+            AssemblyError::UnmappedChar { c, charset, .. } => {
+                write!(f, "character {:?} has no code in {}", c, charset)
+            }
+            AssemblyError::InvalidAddressingMode { mnemonic, mode, .. } => {
+                write!(f, "`{}` does not support {} addressing", mnemonic, mode.name())
+            }
+            AssemblyError::MissingOperand { mnemonic, .. } => write!(f, "`{}` needs an operand", mnemonic),
+            AssemblyError::ForcedSize { mnemonic, mode, bytes, .. } => write!(
+                f,
+                "can not force a {} byte operand for `{}` with {} addressing",
+                bytes, mnemonic, mode.name()
+            ),
+            AssemblyError::UndefinedSymbol { name, .. } => write!(f, "undefined symbol `{}`", name),
+            AssemblyError::CircularDefinition { name, .. } => {
+                write!(f, "`{}` is defined in terms of itself", name)
+            }
+            AssemblyError::DivideByZero { .. } => write!(f, "division by zero"),
+            AssemblyError::Overflow { .. } => write!(f, "arithmetic overflow"),
+            AssemblyError::ValueOutOfRange { value, bytes: 1, .. } => {
+                write!(f, "value {} does not fit in a byte", value)
+            }
+            AssemblyError::ValueOutOfRange { value, bytes, .. } => {
+                write!(f, "value {} does not fit in {} bytes", value, bytes)
+            }
+            AssemblyError::BadSize { count, .. } if *count < 0 => write!(f, "can not skip {} bytes", count),
+            AssemblyError::BadSize { count, pc, .. } => {
+                write!(f, "skipping {} bytes from ${:04X} runs past the end of memory", count, pc)
+            }
+            AssemblyError::PastEndOfMemory { pc, .. } => {
+                write!(f, "code or data from ${:04X} runs past the end of memory", pc)
+            }
+            AssemblyError::BadAlignment { boundary, .. } => write!(f, "can not align to {} bytes", boundary),
+            AssemblyError::Unsettled { directive, .. } if directive == "ORG" => {
+                write!(f, "layout does not settle, the address this `ORG` moves to keeps changing")
+            }
+            AssemblyError::Unsettled { directive, .. } => {
+                write!(f, "layout does not settle, the size of this `{}` keeps changing", directive)
+            }
+            AssemblyError::BranchOutOfRange { .. } => write!(f, "branch target out of range"),
+            AssemblyError::FileNotFound { path, .. } => write!(f, "can not find `{}`", path),
+            AssemblyError::FileError { path, message, .. } => write!(f, "can not read `{}`: {}", path, message),
+            AssemblyError::BinaryRange { path, start, end, size, .. } => write!(
+                f,
+                "can not read bytes {} to {} of `{}`, which is {} bytes long",
+                start, end, path, size
+            ),
+            AssemblyError::CircularInclude { path, .. } => write!(f, "`{}` ends up including itself", path),
+            AssemblyError::Unmatched { directive, opener, .. } => {
+                write!(f, "`{}` without a matching `{}`", directive, opener)
+            }
+            AssemblyError::Unterminated { directive, .. } => write!(f, "`{}` is never closed", directive),
+            AssemblyError::BadRepeat { count, .. } => write!(f, "can not repeat {} times", count),
+            AssemblyError::RecursiveMacro { name, .. } => write!(f, "macro `{}` ends up using itself", name),
+            AssemblyError::BadArguments { message, .. } => write!(f, "{}", message),
+            AssemblyError::UnknownSegment { name, .. } => write!(f, "unknown segment `{}`", name),
+            AssemblyError::SegmentOverflow { name, by, .. } => {
+                write!(f, "segment `{}` overflows its area by {} byte{}", name, by, if *by == 1 { "" } else { "s" })
+            }
+            AssemblyError::NoSegment { .. } => write!(f, "code before the first `.SEGMENT` with no `CODE` segment to go in"),
+            AssemblyError::NotOutput { segment, .. } => {
+                write!(f, "segment `{}` is not output so it can only reserve space", segment)
+            }
+            AssemblyError::AssertionFailed { message: Some(message), .. } => write!(f, "assertion failed: {}", message),
+            AssemblyError::AssertionFailed { message: None, .. } => write!(f, "assertion failed"),
+            AssemblyError::NotRelocatable { directive, .. } => {
+                write!(f, "`{}` can not be used in an object file, where addresses are not known yet", directive)
+            }
+            AssemblyError::OriginInLayout { .. } => {
+                write!(f, "`ORG` can not be used with a layout, which places each segment in its area")
+            }
+            AssemblyError::UserError { message, .. } | AssemblyError::UserWarning { message, .. } => {
+                write!(f, "{}", message)
+            }
+            AssemblyError::DuplicateSymbol { name, .. } => write!(f, "`{}` is already defined", name),
+        }
+    }
+}
+
+impl std::error::Error for AssemblyError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sources(code: &str) -> Sources {
+        Sources {
+            files: vec![SourceFile { name: "prog.asm".to_string(), code: code.to_string() }],
+            expansions: vec![],
+        }
+    }
+
+    fn span(line: usize, column: usize, len: usize) -> Span {
+        Span { file: 0, line, column, len, expansion: 0 }
+    }
+
+    #[test]
+    fn quotes_the_line_and_underlines_the_span() {
+        let error = AssemblyError::UnknownMnemonic { name: "LDZ".to_string(), span: span(1, 8, 3) };
+        assert_eq!(error.render(&sources("START: LDZ #1"), false), "\
+error: unknown opcode `LDZ`
+ --> prog.asm:1:8
+  |
+1 | START: LDZ #1
+  |        ^^^
+  = help: did you mean `LDA`?
+");
+    }
+
+    #[test]
+    fn expands_tabs_before_the_span() {
+        let error = AssemblyError::UndefinedSymbol { name: "X".to_string(), suggestion: None, span: span(1, 6, 1) };
+        assert!(error.render(&sources("\tLDA X"), false).ends_with("\
+  |
+1 |     LDA X
+  |         ^
+"));
+    }
+
+    #[test]
+    fn notes_without_a_span_line_up_with_the_gutter() {
+        let code = format!("{}LDA #$100", "NOP\n".repeat(9));
+        let error = AssemblyError::ValueOutOfRange { value: 256, bytes: 1, span: span(10, 6, 4) };
+        assert!(error.render(&sources(&code), false).ends_with("\
+   |
+10 | LDA #$100
+   |      ^^^^
+   = help: use `<` or `>` to take the low or high byte
+"));
+    }
+
+    #[test]
+    fn notes_can_point_at_other_lines() {
+        let code = "LOOP: NOP\nNOP\nNOP\nNOP\nNOP\nNOP\nNOP\nNOP\nNOP\nLOOP: NOP";
+        let error = AssemblyError::DuplicateSymbol { name: "LOOP".to_string(), span: span(10, 1, 4), previous: span(1, 1, 4) };
+        assert_eq!(error.render(&sources(code), false), "\
+error: `LOOP` is already defined
+  --> prog.asm:10:1
+   |
+10 | LOOP: NOP
+   | ^^^^
+note: previously defined here
+ --> prog.asm:1:1
+  |
+1 | LOOP: NOP
+  | ^^^^
+");
+    }
+
+    #[test]
+    fn suggests_only_close_names() {
+        let names = ["COUNTER", "START", "SCREEN"];
+        assert_eq!(closest("COUNTR", names.iter().copied()), Some("COUNTER"));
+        assert_eq!(closest("SCREN", names.iter().copied()), Some("SCREEN"));
+        assert_eq!(closest("LOOP", names.iter().copied()), None);
+        assert_eq!(closest("counter", names.iter().copied()), Some("COUNTER"));
+    }
+}
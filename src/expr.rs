@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::AddressSize;
+
+/// Operators that take two operands
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BinaryOp {
+    LogicalOr,
+    LogicalAnd,
+    Or,
+    Xor,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+impl BinaryOp {
+    /// Binding strength of the operator, higher binds tighter
+    pub fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::LogicalOr => 1,
+            BinaryOp::LogicalAnd => 2,
+            BinaryOp::Or => 3,
+            BinaryOp::Xor => 4,
+            BinaryOp::And => 5,
+            BinaryOp::Equal | BinaryOp::NotEqual => 6,
+            BinaryOp::Less | BinaryOp::LessEqual | BinaryOp::Greater | BinaryOp::GreaterEqual => 7,
+            BinaryOp::ShiftLeft | BinaryOp::ShiftRight => 8,
+            BinaryOp::Add | BinaryOp::Subtract => 9,
+            BinaryOp::Multiply | BinaryOp::Divide | BinaryOp::Modulo => 10,
+        }
+    }
+
+    pub fn from_symbol(symbol: &str) -> Option<BinaryOp> {
+        Some(match symbol {
+            "||" => BinaryOp::LogicalOr,
+            "&&" => BinaryOp::LogicalAnd,
+            "|" => BinaryOp::Or,
+            "^" => BinaryOp::Xor,
+            "&" => BinaryOp::And,
+            "==" => BinaryOp::Equal,
+            "!=" => BinaryOp::NotEqual,
+            "<" => BinaryOp::Less,
+            "<=" => BinaryOp::LessEqual,
+            ">" => BinaryOp::Greater,
+            ">=" => BinaryOp::GreaterEqual,
+            "<<" => BinaryOp::ShiftLeft,
+            ">>" => BinaryOp::ShiftRight,
+            "+" => BinaryOp::Add,
+            "-" => BinaryOp::Subtract,
+            "*" => BinaryOp::Multiply,
+            "/" => BinaryOp::Divide,
+            "%" => BinaryOp::Modulo,
+            _ => return None,
+        })
+    }
+
+    /// Comparisons and logical operators always produce `0` or `1`
+    fn is_boolean(self) -> bool {
+        self.precedence() <= 2 || (6..=7).contains(&self.precedence())
+    }
+}
+
+/// Operators that take a single operand
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UnaryOp {
+    /// `-`
+    Negate,
+    /// `~`
+    Complement,
+    /// `!`
+    Not,
+    /// `<`, the least-significant byte
+    Low,
+    /// `>`, the most-significant byte
+    High,
+}
+
+/// An unevaluated expression, kept around so symbols defined later in the
+/// program can be resolved in a later pass.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    /// A literal along with the size implied by how many digits it was written with
+    Number(i64, AddressSize),
+    /// A character literal, replaced by its code in the current character set
+    /// once the statement is encoded
+    Char(char),
+    /// A label or define
+    Symbol(String),
+    /// The address of the current instruction, written as `*`
+    Pc,
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+/// Reasons an expression could not be parsed or evaluated
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ExprError {
+    Undefined(String),
+    Circular(String),
+    DivideByZero,
+    /// A result that does not fit in the 64 bits values are worked out in
+    Overflow,
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExprError::Undefined(name) => write!(f, "Undefined symbol: {}", name),
+            ExprError::Circular(name) => write!(f, "Symbol is defined in terms of itself: {}", name),
+            ExprError::DivideByZero => write!(f, "Division by zero"),
+            ExprError::Overflow => write!(f, "Arithmetic overflow"),
+        }
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+/// Supplies values for symbols and the current address while evaluating
+pub trait Context {
+    /// Returns the value of a symbol, or an error if it can not be resolved yet
+    fn symbol(&self, name: &str) -> Result<i64, ExprError>;
+    /// Returns the size of a symbol, `AddressSize::Unknown` if it depends on a label
+    fn symbol_size(&self, name: &str) -> AddressSize;
+    /// Returns the address of the current instruction
+    fn pc(&self) -> i64;
+}
+
+impl Expr {
+    pub fn evaluate(&self, ctx: &dyn Context) -> Result<i64, ExprError> {
+        Ok(match self {
+            Expr::Number(n, _) => *n,
+            Expr::Char(c) => *c as i64,
+            Expr::Symbol(name) => ctx.symbol(name)?,
+            Expr::Pc => ctx.pc(),
+            Expr::Unary(op, e) => {
+                let v = e.evaluate(ctx)?;
+                match op {
+                    UnaryOp::Negate => v.checked_neg().ok_or(ExprError::Overflow)?,
+                    UnaryOp::Complement => !v,
+                    UnaryOp::Not => (v == 0) as i64,
+                    UnaryOp::Low => v & 0xff,
+                    UnaryOp::High => (v >> 8) & 0xff,
+                }
+            }
+            Expr::Binary(op, l, r) => {
+                let l = l.evaluate(ctx)?;
+                let r = r.evaluate(ctx)?;
+                match op {
+                    BinaryOp::LogicalOr => (l != 0 || r != 0) as i64,
+                    BinaryOp::LogicalAnd => (l != 0 && r != 0) as i64,
+                    BinaryOp::Or => l | r,
+                    BinaryOp::Xor => l ^ r,
+                    BinaryOp::And => l & r,
+                    BinaryOp::Equal => (l == r) as i64,
+                    BinaryOp::NotEqual => (l != r) as i64,
+                    BinaryOp::Less => (l < r) as i64,
+                    BinaryOp::LessEqual => (l <= r) as i64,
+                    BinaryOp::Greater => (l > r) as i64,
+                    BinaryOp::GreaterEqual => (l >= r) as i64,
+                    BinaryOp::ShiftLeft => if (0..64).contains(&r) { l << r } else { 0 },
+                    BinaryOp::ShiftRight => if (0..64).contains(&r) { l >> r } else { 0 },
+                    BinaryOp::Add => l.checked_add(r).ok_or(ExprError::Overflow)?,
+                    BinaryOp::Subtract => l.checked_sub(r).ok_or(ExprError::Overflow)?,
+                    BinaryOp::Multiply => l.checked_mul(r).ok_or(ExprError::Overflow)?,
+                    BinaryOp::Divide | BinaryOp::Modulo if r == 0 => return Err(ExprError::DivideByZero),
+                    BinaryOp::Divide => l.checked_div(r).ok_or(ExprError::Overflow)?,
+                    BinaryOp::Modulo => l.checked_rem(r).ok_or(ExprError::Overflow)?,
+                }
+            }
+        })
+    }
+
+    /// Replaces every character literal with its code, returning the first
+    /// character that has none
+    pub fn encode_chars(&mut self, encode: &dyn Fn(char) -> Option<u8>) -> Result<(), char> {
+        match self {
+            Expr::Char(c) => {
+                let code = encode(*c).ok_or(*c)?;
+                *self = Expr::Number(code as i64, AddressSize::U8);
+            }
+            Expr::Unary(_, e) => e.encode_chars(encode)?,
+            Expr::Binary(_, l, r) => {
+                l.encode_chars(encode)?;
+                r.encode_chars(encode)?;
+            }
+            Expr::Number(..) | Expr::Symbol(_) | Expr::Pc => {}
+        }
+        Ok(())
+    }
+
+    /// Replaces every symbol that has a value in `values`, used to fill in the
+    /// parameters and local labels of a macro
+    pub fn substitute(&mut self, values: &HashMap<String, Expr>) {
+        match self {
+            Expr::Symbol(name) => {
+                if let Some(value) = values.get(name) {
+                    *self = value.clone();
+                }
+            }
+            Expr::Unary(_, e) => e.substitute(values),
+            Expr::Binary(_, l, r) => {
+                l.substitute(values);
+                r.substitute(values);
+            }
+            Expr::Number(..) | Expr::Char(_) | Expr::Pc => {}
+        }
+    }
+
+    /// Returns if the expression has no symbols or `*`, so its value is known
+    /// without knowing where anything is placed
+    pub fn is_constant(&self) -> bool {
+        match self {
+            Expr::Number(..) | Expr::Char(_) => true,
+            Expr::Symbol(_) | Expr::Pc => false,
+            Expr::Unary(_, e) => e.is_constant(),
+            Expr::Binary(_, l, r) => l.is_constant() && r.is_constant(),
+        }
+    }
+
+    /// Infers whether the expression fits in a zero-page operand. Literals keep
+    /// the width they were written with, so `$0010` is still a 16 bit value.
+    pub fn size(&self, ctx: &dyn Context) -> AddressSize {
+        let size = match self {
+            Expr::Number(_, size) => *size,
+            Expr::Char(_) => AddressSize::U8,
+            Expr::Symbol(name) => ctx.symbol_size(name),
+            Expr::Pc => AddressSize::U16,
+            Expr::Unary(UnaryOp::Low, _) | Expr::Unary(UnaryOp::High, _) => return AddressSize::U8,
+            Expr::Unary(_, e) => e.size(ctx),
+            Expr::Binary(op, l, r) => {
+                let size = l.size(ctx).max(r.size(ctx));
+                if op.is_boolean() && size != AddressSize::Unknown {
+                    return AddressSize::U8;
+                }
+                size
+            }
+        };
+        match (size, self.evaluate(ctx)) {
+            (AddressSize::Unknown, _) | (_, Err(_)) => AddressSize::Unknown,
+            (AddressSize::U8, Ok(v)) if !(0..=0xff).contains(&v) => AddressSize::U16,
+            (size, Ok(_)) => size,
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Number(n, _) => write!(f, "{}", n),
+            Expr::Char(c) => write!(f, "'{}'", c),
+            Expr::Symbol(name) => write!(f, "{}", name),
+            Expr::Pc => write!(f, "*"),
+            Expr::Unary(op, e) => {
+                let symbol = match op {
+                    UnaryOp::Negate => "-",
+                    UnaryOp::Complement => "~",
+                    UnaryOp::Not => "!",
+                    UnaryOp::Low => "<",
+                    UnaryOp::High => ">",
+                };
+                write!(f, "{}{}", symbol, e)
+            }
+            Expr::Binary(op, l, r) => write!(f, "({:?} {} {})", op, l, r),
+        }
+    }
+}
+
+/// Decodes asm formatted numbers (`$` hex, `%` binary, leading `0` octal, or
+/// decimal) along with the size implied by the number of digits.
+pub fn parse_number(num: &str) -> Option<(i64, AddressSize)> {
+    let (digits, radix, byte_digits) = if let Some(hex) = num.strip_prefix('$') {
+        (hex, 16, 2)
+    } else if let Some(bin) = num.strip_prefix('%') {
+        (bin, 2, 8)
+    } else if num.len() > 1 && num.starts_with('0') {
+        (&num[1..], 8, 3)
+    } else {
+        (num, 10, 3)
+    };
+
+    if digits.is_empty() {
+        return None;
+    }
+    let value = i64::from_str_radix(digits, radix).ok()?;
+    let size = if value > 0xff || (radix != 10 && digits.len() > byte_digits) {
+        AddressSize::U16
+    } else {
+        AddressSize::U8
+    };
+    Some((value, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Symbols with fixed values, where names starting with `L` are labels
+    /// whose size is not known yet
+    struct Values(HashMap<&'static str, i64>);
+
+    impl Context for Values {
+        fn symbol(&self, name: &str) -> Result<i64, ExprError> {
+            self.0.get(name).copied().ok_or_else(|| ExprError::Undefined(name.to_string()))
+        }
+
+        fn symbol_size(&self, name: &str) -> AddressSize {
+            match self.0.get(name) {
+                Some(_) if name.starts_with('L') => AddressSize::Unknown,
+                Some(&value) if (0..=0xff).contains(&value) => AddressSize::U8,
+                Some(_) => AddressSize::U16,
+                None => AddressSize::Unknown,
+            }
+        }
+
+        fn pc(&self) -> i64 {
+            0x8000
+        }
+    }
+
+    fn values() -> Values {
+        Values([("ZP", 0x10), ("ABS", 0x1234), ("LABEL", 0x20)].iter().copied().collect())
+    }
+
+    fn num(n: i64) -> Box<Expr> {
+        Box::new(Expr::Number(n, if n > 0xff { AddressSize::U16 } else { AddressSize::U8 }))
+    }
+
+    fn symbol(name: &str) -> Box<Expr> {
+        Box::new(Expr::Symbol(name.to_string()))
+    }
+
+    fn binary(op: BinaryOp, l: Box<Expr>, r: Box<Expr>) -> Box<Expr> {
+        Box::new(Expr::Binary(op, l, r))
+    }
+
+    #[test]
+    fn precedence_orders_operators_like_c() {
+        let order = [
+            BinaryOp::LogicalOr,
+            BinaryOp::LogicalAnd,
+            BinaryOp::Or,
+            BinaryOp::Xor,
+            BinaryOp::And,
+            BinaryOp::Equal,
+            BinaryOp::Less,
+            BinaryOp::ShiftLeft,
+            BinaryOp::Add,
+            BinaryOp::Multiply,
+        ];
+        for pair in order.windows(2) {
+            assert!(pair[0].precedence() < pair[1].precedence(), "{:?} should bind looser than {:?}", pair[0], pair[1]);
+        }
+        assert_eq!(BinaryOp::Modulo.precedence(), BinaryOp::Divide.precedence());
+        assert_eq!(BinaryOp::GreaterEqual.precedence(), BinaryOp::Less.precedence());
+    }
+
+    #[test]
+    fn evaluates_operators() {
+        let ctx = values();
+        assert_eq!(binary(BinaryOp::Add, num(1), binary(BinaryOp::Multiply, num(2), num(3))).evaluate(&ctx), Ok(7));
+        assert_eq!(Expr::Unary(UnaryOp::High, num(0x1234)).evaluate(&ctx), Ok(0x12));
+        assert_eq!(Expr::Unary(UnaryOp::Low, num(0x1234)).evaluate(&ctx), Ok(0x34));
+        assert_eq!(Expr::Unary(UnaryOp::Not, num(5)).evaluate(&ctx), Ok(0));
+        assert_eq!(binary(BinaryOp::ShiftLeft, num(1), num(64)).evaluate(&ctx), Ok(0));
+        assert_eq!(binary(BinaryOp::Less, symbol("ZP"), symbol("ABS")).evaluate(&ctx), Ok(1));
+        assert_eq!(Expr::Pc.evaluate(&ctx), Ok(0x8000));
+    }
+
+    #[test]
+    fn reports_errors() {
+        let ctx = values();
+        assert_eq!(binary(BinaryOp::Divide, num(1), num(0)).evaluate(&ctx), Err(ExprError::DivideByZero));
+        assert_eq!(binary(BinaryOp::Modulo, num(1), num(0)).evaluate(&ctx), Err(ExprError::DivideByZero));
+        assert_eq!(binary(BinaryOp::Multiply, num(1 << 62), num(2)).evaluate(&ctx), Err(ExprError::Overflow));
+        assert_eq!(binary(BinaryOp::Divide, num(i64::MIN), num(-1)).evaluate(&ctx), Err(ExprError::Overflow));
+        assert_eq!(Expr::Unary(UnaryOp::Negate, num(i64::MIN)).evaluate(&ctx), Err(ExprError::Overflow));
+        assert_eq!(binary(BinaryOp::Add, symbol("MISSING"), num(1)).evaluate(&ctx), Err(ExprError::Undefined("MISSING".to_string())));
+    }
+
+    #[test]
+    fn comparisons_are_zero_page_sized() {
+        let ctx = values();
+        assert_eq!(binary(BinaryOp::Equal, symbol("ABS"), symbol("ABS")).size(&ctx), AddressSize::U8);
+        assert_eq!(binary(BinaryOp::LogicalAnd, symbol("ABS"), num(1)).size(&ctx), AddressSize::U8);
+        assert_eq!(binary(BinaryOp::Subtract, symbol("ABS"), symbol("ABS")).size(&ctx), AddressSize::U16);
+        // A label may still move, so whether it is in zero page is not known
+        assert_eq!(binary(BinaryOp::Equal, symbol("LABEL"), num(1)).size(&ctx), AddressSize::Unknown);
+    }
+
+    #[test]
+    fn size_grows_when_the_value_does_not_fit() {
+        let ctx = values();
+        assert_eq!(symbol("ZP").size(&ctx), AddressSize::U8);
+        assert_eq!(binary(BinaryOp::Add, symbol("ZP"), num(1)).size(&ctx), AddressSize::U8);
+        assert_eq!(binary(BinaryOp::Multiply, symbol("ZP"), num(0x10)).size(&ctx), AddressSize::U16);
+        assert_eq!(Expr::Unary(UnaryOp::High, symbol("ABS")).size(&ctx), AddressSize::U8);
+        assert_eq!(Expr::Number(0x10, AddressSize::U16).size(&ctx), AddressSize::U16);
+        assert_eq!(symbol("MISSING").size(&ctx), AddressSize::Unknown);
+    }
+
+    #[test]
+    fn numbers_keep_the_width_they_are_written_with() {
+        assert_eq!(parse_number("$10"), Some((0x10, AddressSize::U8)));
+        assert_eq!(parse_number("$0010"), Some((0x10, AddressSize::U16)));
+        assert_eq!(parse_number("%00000001"), Some((1, AddressSize::U8)));
+        assert_eq!(parse_number("%000000001"), Some((1, AddressSize::U16)));
+        assert_eq!(parse_number("0377"), Some((0o377, AddressSize::U8)));
+        assert_eq!(parse_number("00377"), Some((0o377, AddressSize::U16)));
+        assert_eq!(parse_number("255"), Some((255, AddressSize::U8)));
+        assert_eq!(parse_number("256"), Some((256, AddressSize::U16)));
+        assert_eq!(parse_number("0"), Some((0, AddressSize::U8)));
+    }
+
+    #[test]
+    fn rejects_bad_numbers() {
+        assert_eq!(parse_number("$"), None);
+        assert_eq!(parse_number("$1G"), None);
+        assert_eq!(parse_number("%102"), None);
+        assert_eq!(parse_number("089"), None);
+        assert_eq!(parse_number("12AB"), None);
+    }
+
+    #[test]
+    fn characters_are_replaced_by_their_code() {
+        let upper = |c: char| if c.is_ascii_lowercase() { Some(c.to_ascii_uppercase() as u8) } else { None };
+        let mut expr = *binary(BinaryOp::Add, Box::new(Expr::Char('a')), Box::new(Expr::Unary(UnaryOp::Low, Box::new(Expr::Char('b')))));
+        assert_eq!(expr.encode_chars(&upper), Ok(()));
+        assert_eq!(expr.evaluate(&values()), Ok(0x41 + 0x42));
+
+        let mut expr = *binary(BinaryOp::Add, Box::new(Expr::Char('a')), Box::new(Expr::Char('!')));
+        assert_eq!(expr.encode_chars(&upper), Err('!'));
+    }
+
+    #[test]
+    fn substitutes_symbols() {
+        let values: HashMap<String, Expr> = vec![("ADDR".to_string(), *num(0x1234))].into_iter().collect();
+        let mut expr = *binary(BinaryOp::Add, symbol("ADDR"), binary(BinaryOp::Multiply, symbol("ZP"), symbol("ADDR")));
+        expr.substitute(&values);
+        assert_eq!(expr, *binary(BinaryOp::Add, num(0x1234), binary(BinaryOp::Multiply, symbol("ZP"), num(0x1234))));
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+
+use crate::error::AssemblyError;
+use crate::expr::parse_number;
+use crate::lexer::Span;
+
+/// The segment that lines before the first `.SEGMENT` go in
+pub const DEFAULT_SEGMENT: &str = "CODE";
+
+/// An area of memory that the lines of one segment are placed in
+#[derive(Clone)]
+pub struct Segment {
+    pub name: String,
+    pub start: u16,
+    /// Number of bytes in the area, up to `$10000`
+    pub size: u32,
+    /// The byte that pads the area out to its full size, when it should be
+    pub fill: Option<u8>,
+    /// Whether the bytes are output, which is not the case for zero page and
+    /// other RAM that space is only reserved in
+    pub output: bool,
+}
+
+/// Reads a memory layout with one segment per line, made of its name followed
+/// by its settings, such as `CODE start=$8000 size=$4000 fill=$FF`. `start`
+/// and `size` are required, `output=no` leaves the segment out of the output,
+/// and anything after a `;` is a comment.
+pub fn parse(text: &str, file: usize) -> Result<Vec<Segment>, Vec<AssemblyError>> {
+    let mut segments: Vec<Segment> = vec![];
+    let mut declared: HashMap<String, Span> = HashMap::new();
+    let mut errors = vec![];
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or_default();
+        let mut words = words(line).into_iter().map(|(column, word)| {
+            let span = Span { file, line: index + 1, column, len: word.chars().count(), expansion: 0 };
+            (word, span)
+        });
+        let (name, name_span) = match words.next() {
+            Some(name) => name,
+            None => continue,
+        };
+
+        match segment(name, name_span, words) {
+            Ok(segment) => match declared.get(name) {
+                Some(previous) => errors.push(AssemblyError::DuplicateSymbol {
+                    name: name.to_string(),
+                    span: name_span,
+                    previous: *previous,
+                }),
+                None => {
+                    declared.insert(name.to_string(), name_span);
+                    segments.push(segment);
+                }
+            },
+            Err(e) => errors.push(e),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(segments)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Reads the settings following the name of a segment
+fn segment<'a>(
+    name: &str,
+    name_span: Span,
+    settings: impl Iterator<Item = (&'a str, Span)>,
+) -> Result<Segment, AssemblyError> {
+    let mut start = None;
+    let mut size = None;
+    let mut fill = None;
+    let mut output = true;
+
+    for (setting, span) in settings {
+        let error = |message: String| AssemblyError::Syntax { message, span };
+        let (key, value) = setting.split_once('=')
+            .ok_or_else(|| error(format!("expected `setting=value` but found `{}`", setting)))?;
+        let number = |max: i64| match parse_number(value) {
+            Some((number, _)) if number <= max => Ok(number),
+            Some((number, _)) => Err(error(format!("`{}` can be at most ${:X} but is ${:X}", key, max, number))),
+            None => Err(AssemblyError::BadNumber { text: value.to_string(), span }),
+        };
+
+        match key.to_lowercase().as_str() {
+            "start" => start = Some(number(0xffff)? as u16),
+            "size" => size = Some(number(0x10000)? as u32),
+            "fill" => fill = Some(number(0xff)? as u8),
+            "output" => {
+                output = match value.to_lowercase().as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err(error(format!("expected `yes` or `no` but found `{}`", value))),
+                }
+            }
+            _ => {
+                return Err(error(format!(
+                    "unknown setting `{}`, the settings are start, size, fill and output",
+                    key
+                )))
+            }
+        }
+    }
+
+    match (start, size) {
+        (Some(start), Some(size)) if start as u32 + size > 0x10000 => Err(AssemblyError::Syntax {
+            message: format!("segment `{}` runs past the end of memory", name),
+            span: name_span,
+        }),
+        (Some(start), Some(size)) => Ok(Segment { name: name.to_string(), start, size, fill, output }),
+        _ => Err(AssemblyError::Syntax {
+            message: format!("segment `{}` needs a `start` and a `size`", name),
+            span: name_span,
+        }),
+    }
+}
+
+/// Splits a line on whitespace, returning each word along with the column it
+/// starts at
+fn words(line: &str) -> Vec<(usize, &str)> {
+    let mut words = vec![];
+    let mut start = None;
+    for (column, (i, c)) in line.char_indices().chain(std::iter::once((line.len(), ' '))).enumerate() {
+        match (start, c.is_whitespace()) {
+            (None, false) => start = Some((column + 1, i)),
+            (Some((word_column, word_start)), true) => {
+                words.push((word_column, &line[word_start..i]));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(text: &str) -> Vec<AssemblyError> {
+        match parse(text, 0) {
+            Err(errors) => errors,
+            Ok(_) => panic!("`{}` should not parse", text),
+        }
+    }
+
+    fn error(text: &str) -> String {
+        errors(text)[0].to_string()
+    }
+
+    #[test]
+    fn reads_one_segment_per_line() {
+        let segments = parse("; name settings\nZP start=$00 size=$100 output=no\n\nCODE  size=$4000 start=$C000 fill=$FF ; rom\n", 0).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!((segments[0].name.as_str(), segments[0].start, segments[0].size), ("ZP", 0, 0x100));
+        assert_eq!((segments[0].fill, segments[0].output), (None, false));
+        assert_eq!((segments[1].name.as_str(), segments[1].start, segments[1].size), ("CODE", 0xc000, 0x4000));
+        assert_eq!((segments[1].fill, segments[1].output), (Some(0xff), true));
+    }
+
+    #[test]
+    fn settings_are_case_insensitive() {
+        let segments = parse("RAM START=$0200 Size=16 OUTPUT=No", 0).unwrap();
+        assert_eq!((segments[0].start, segments[0].size, segments[0].output), (0x200, 16, false));
+    }
+
+    #[test]
+    fn start_and_size_are_required() {
+        assert_eq!(error("CODE start=$8000"), "segment `CODE` needs a `start` and a `size`");
+        assert_eq!(error("CODE size=$8000"), "segment `CODE` needs a `start` and a `size`");
+    }
+
+    #[test]
+    fn areas_must_fit_in_memory() {
+        assert!(parse("ALL start=0 size=$10000\nTOP start=$FFFA size=6", 0).is_ok());
+        assert_eq!(error("CODE start=$FFFA size=7"), "segment `CODE` runs past the end of memory");
+        assert_eq!(error("CODE start=$10000 size=1"), "`start` can be at most $FFFF but is $10000");
+        assert_eq!(error("CODE start=0 fill=$100 size=1"), "`fill` can be at most $FF but is $100");
+    }
+
+    #[test]
+    fn segments_are_declared_once() {
+        let errors = errors("CODE start=0 size=1\nDATA start=1 size=1\nCODE start=2 size=1");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0], AssemblyError::DuplicateSymbol {
+            name: "CODE".to_string(),
+            span: Span { file: 0, line: 3, column: 1, len: 4, expansion: 0 },
+            previous: Span { file: 0, line: 1, column: 1, len: 4, expansion: 0 },
+        });
+    }
+
+    #[test]
+    fn bad_settings_are_reported() {
+        assert_eq!(error("CODE start=0 size=1 bank=2"), "unknown setting `bank`, the settings are start, size, fill and output");
+        assert_eq!(error("CODE start=0 size=1 output=maybe"), "expected `yes` or `no` but found `maybe`");
+        assert_eq!(error("CODE start=0 size"), "expected `setting=value` but found `size`");
+        assert_eq!(error("CODE start=$G size=1"), "invalid number `$G`");
+    }
+
+    #[test]
+    fn errors_point_at_the_setting() {
+        let errors = errors("CODE start=0  size=$1G");
+        assert_eq!(errors[0].span(), Span { file: 0, line: 1, column: 15, len: 8, expansion: 0 });
+    }
+}
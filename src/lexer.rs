@@ -0,0 +1,414 @@
+use std::fmt;
+
+use crate::error::AssemblyError;
+use crate::expr::parse_number;
+use crate::AddressSize;
+
+/// A location in the source code, used to point diagnostics at the right place
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Span {
+    /// Index of the file in the list of source files
+    pub file: usize,
+    /// Line number starting at 1
+    pub line: usize,
+    /// Column number starting at 1
+    pub column: usize,
+    /// Number of characters covered
+    pub len: usize,
+    /// One more than the index of the macro call that produced the span, or
+    /// zero when it was written directly
+    pub expansion: usize,
+}
+
+impl Span {
+    /// Creates a span covering everything from the start of `self` to the end of `other`
+    pub fn to(self, other: Span) -> Span {
+        if other.line != self.line || other.column < self.column {
+            return self;
+        }
+        Span {
+            len: other.column + other.len - self.column,
+            ..self
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenKind {
+    /// Opcodes, labels, defines and registers
+    Ident(String),
+    /// A number along with the size implied by how many digits it was written with
+    Number(i64, AddressSize),
+    /// Text inside double quotes
+    Str(Vec<Quoted>),
+    /// A character inside single quotes
+    Char(char),
+    /// Any operator, including `#`, `=` and `*`
+    Operator(&'static str),
+    Comma,
+    Colon,
+    LParen,
+    RParen,
+    /// Everything after a `;`
+    Comment(String),
+    Newline,
+}
+
+/// One character of a quoted literal
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Quoted {
+    /// A character, stored with its code in the current character set
+    Char(char),
+    /// A byte written with `\xNN`, stored as it is
+    Byte(u8),
+}
+
+/// The text of a quoted literal, with bytes taken as the character with the
+/// same code
+pub fn unquote(text: &[Quoted]) -> String {
+    text.iter()
+        .map(|quoted| match quoted {
+            Quoted::Char(c) => *c,
+            Quoted::Byte(byte) => char::from(*byte),
+        })
+        .collect()
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenKind::Ident(name) => write!(f, "'{}'", name),
+            TokenKind::Number(n, _) => write!(f, "'{}'", n),
+            TokenKind::Str(text) => write!(f, "\"{}\"", unquote(text)),
+            TokenKind::Char(c) => write!(f, "'{}'", c),
+            TokenKind::Operator(op) => write!(f, "'{}'", op),
+            TokenKind::Comma => write!(f, "','"),
+            TokenKind::Colon => write!(f, "':'"),
+            TokenKind::LParen => write!(f, "'('"),
+            TokenKind::RParen => write!(f, "')'"),
+            TokenKind::Comment(_) => write!(f, "comment"),
+            TokenKind::Newline => write!(f, "end of line"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+/// Operators ordered so that longer symbols are matched first
+const OPERATORS: [&str; 22] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||",
+    "+", "-", "*", "/", "%", "&", "|", "^", "~", "!", "<", ">", "=", "#",
+];
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.'
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+/// Splits source code into tokens, every line ends with a `Newline` token. A
+/// line with an error is cut short at the error, which is recorded.
+pub fn lex(code: &str, file: usize, errors: &mut Vec<AssemblyError>) -> Vec<Token> {
+    let mut tokens = Vec::new();
+
+    for (line_index, line) in code.lines().enumerate() {
+        if let Err(e) = lex_line(line, line_index + 1, file, &mut tokens) {
+            errors.push(e);
+        }
+
+        tokens.push(Token {
+            kind: TokenKind::Newline,
+            span: Span { file, line: line_index + 1, column: line.chars().count() + 1, len: 0, expansion: 0 },
+        });
+    }
+
+    tokens
+}
+
+fn lex_line(line: &str, line_number: usize, file: usize, tokens: &mut Vec<Token>) -> Result<(), AssemblyError> {
+    let chars: Vec<(usize, char)> = line.char_indices().collect();
+    // Index into `chars`, which is also the column number minus one
+    let mut i = 0;
+
+    while i < chars.len() {
+        let (start, c) = chars[i];
+        let span = |len: usize| Span { file, line: line_number, column: i + 1, len, expansion: 0 };
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let rest = &line[start..];
+        let after_value = matches!(
+            tokens.last(),
+            Some(Token { kind: TokenKind::Number(..), .. })
+                | Some(Token { kind: TokenKind::Ident(_), .. })
+                | Some(Token { kind: TokenKind::Char(_), .. })
+                | Some(Token { kind: TokenKind::RParen, .. })
+        );
+        let spaced_before = i > 0 && chars[i - 1].1.is_whitespace();
+        // `%` is a binary prefix when it starts a value, so `LDA %0101` is a
+        // number but `A%2` and `A % 2` are modulo
+        let binary_literal = c == '%'
+            && rest[1..].starts_with(['0', '1'])
+            && (!after_value || spaced_before);
+
+        let (kind, len) = if c == ';' {
+            (TokenKind::Comment(rest[1..].to_string()), rest.len())
+        } else if c == '$' || c.is_ascii_digit() || binary_literal {
+            let len = 1 + rest[1..].find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len() - 1);
+            match parse_number(&rest[..len]) {
+                Some((value, size)) => (TokenKind::Number(value, size), len),
+                None => {
+                    return Err(AssemblyError::BadNumber {
+                        text: rest[..len].to_string(),
+                        span: span(len),
+                    })
+                }
+            }
+        } else if is_ident_start(c) {
+            let len = rest.find(|c: char| !is_ident_char(c)).unwrap_or(rest.len());
+            (TokenKind::Ident(rest[..len].to_string()), len)
+        } else if c == '"' || c == '\'' {
+            let (text, len) = quoted(&chars[i..], line_number, file, i + 1)?;
+            let kind = if c == '"' {
+                TokenKind::Str(text)
+            } else {
+                match text.as_slice() {
+                    [Quoted::Char(c)] => TokenKind::Char(*c),
+                    [Quoted::Byte(byte)] => TokenKind::Number(*byte as i64, AddressSize::U8),
+                    _ => {
+                        return Err(AssemblyError::Syntax {
+                            message: "character literals must contain a single character".to_string(),
+                            span: span(len),
+                        })
+                    }
+                }
+            };
+            (kind, rest.char_indices().nth(len).map_or(rest.len(), |(end, _)| end))
+        } else if c == ',' {
+            (TokenKind::Comma, 1)
+        } else if c == ':' {
+            (TokenKind::Colon, 1)
+        } else if c == '(' {
+            (TokenKind::LParen, 1)
+        } else if c == ')' {
+            (TokenKind::RParen, 1)
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            (TokenKind::Operator(op), op.len())
+        } else {
+            return Err(AssemblyError::Syntax {
+                message: format!("unexpected character '{}'", c),
+                span: span(1),
+            });
+        };
+
+        // `len` is in bytes but spans count characters
+        let char_len = rest[..len].chars().count();
+        tokens.push(Token { kind, span: span(char_len) });
+        i += char_len;
+    }
+
+    Ok(())
+}
+
+/// Reads a quoted literal starting at the opening quote, returning its contents
+/// and the number of characters including both quotes. Strings and characters
+/// may contain the escapes `\n`, `\r`, `\t`, `\0`, `\\`, `\"`, `\'` and `\xNN`.
+fn quoted(chars: &[(usize, char)], line: usize, file: usize, column: usize) -> Result<(Vec<Quoted>, usize), AssemblyError> {
+    let quote = chars[0].1;
+    let span = |offset: usize, len: usize| Span { file, line, column: column + offset, len, expansion: 0 };
+    let mut text = vec![];
+    let mut i = 1;
+
+    loop {
+        let c = match chars.get(i) {
+            Some(&(_, c)) => c,
+            None => {
+                return Err(AssemblyError::Syntax {
+                    message: "missing closing quote".to_string(),
+                    span: span(0, chars.len()),
+                })
+            }
+        };
+        i += 1;
+
+        if c == quote {
+            return Ok((text, i));
+        } else if c != '\\' {
+            text.push(Quoted::Char(c));
+            continue;
+        }
+
+        let escape = chars.get(i).map(|&(_, c)| c);
+        i += 1;
+        text.push(match escape {
+            Some('n') => Quoted::Char('\n'),
+            Some('r') => Quoted::Char('\r'),
+            Some('t') => Quoted::Char('\t'),
+            Some('0') => Quoted::Char('\0'),
+            Some(c @ '\\') | Some(c @ '"') | Some(c @ '\'') => Quoted::Char(c),
+            Some('x') => {
+                let digits: String = chars.iter().skip(i).take(2).map(|&(_, c)| c).collect();
+                match u8::from_str_radix(&digits, 16) {
+                    Ok(byte) if digits.len() == 2 && digits.chars().all(|c| c.is_ascii_hexdigit()) => {
+                        i += 2;
+                        Quoted::Byte(byte)
+                    }
+                    _ => {
+                        return Err(AssemblyError::Syntax {
+                            message: "expected two hex digits after `\\x`".to_string(),
+                            span: span(i - 2, 2 + digits.len()),
+                        })
+                    }
+                }
+            }
+            Some(c) => {
+                return Err(AssemblyError::Syntax {
+                    message: format!("unknown escape sequence `\\{}`", c),
+                    span: span(i - 2, 2),
+                })
+            }
+            None => {
+                return Err(AssemblyError::Syntax {
+                    message: "missing closing quote".to_string(),
+                    span: span(0, chars.len()),
+                })
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The kinds of the tokens on a line, without the newline at the end
+    fn kinds(line: &str) -> Vec<TokenKind> {
+        let mut errors = vec![];
+        let mut tokens = lex(line, 0, &mut errors);
+        assert_eq!(errors, vec![]);
+        tokens.pop();
+        tokens.into_iter().map(|token| token.kind).collect()
+    }
+
+    /// The first error on a line
+    fn error(line: &str) -> String {
+        let mut errors = vec![];
+        lex(line, 0, &mut errors);
+        match errors.first() {
+            Some(e) => e.to_string(),
+            None => panic!("`{}` should not lex", line),
+        }
+    }
+
+    fn ident(name: &str) -> TokenKind {
+        TokenKind::Ident(name.to_string())
+    }
+
+    #[test]
+    fn percent_starting_a_value_is_binary() {
+        assert_eq!(kinds("LDA %0101"), vec![ident("LDA"), TokenKind::Number(5, AddressSize::U8)]);
+        assert_eq!(kinds("LDA #%1"), vec![ident("LDA"), TokenKind::Operator("#"), TokenKind::Number(1, AddressSize::U8)]);
+        assert_eq!(kinds("X = 1+%10")[4], TokenKind::Number(2, AddressSize::U8));
+    }
+
+    #[test]
+    fn percent_after_a_value_is_modulo() {
+        let modulo = vec![ident("A"), TokenKind::Operator("%"), TokenKind::Number(10, AddressSize::U8)];
+        assert_eq!(kinds("A%10"), modulo);
+        assert_eq!(kinds("A % 10"), modulo);
+        assert_eq!(kinds("(A)%10")[3], TokenKind::Operator("%"));
+        // A space before and none after reads as the start of a binary number
+        assert_eq!(kinds("A %10"), vec![ident("A"), TokenKind::Number(2, AddressSize::U8)]);
+        // Only `0` and `1` can start a binary number
+        assert_eq!(kinds("A %B")[1], TokenKind::Operator("%"));
+    }
+
+    #[test]
+    fn every_line_ends_with_a_newline() {
+        let tokens = lex("NOP\n\nRTS ; done", 0, &mut vec![]);
+        let kinds: Vec<&TokenKind> = tokens.iter().map(|token| &token.kind).collect();
+        assert_eq!(kinds, vec![
+            &ident("NOP"),
+            &TokenKind::Newline,
+            &TokenKind::Newline,
+            &ident("RTS"),
+            &TokenKind::Comment(" done".to_string()),
+            &TokenKind::Newline,
+        ]);
+        assert_eq!(tokens[3].span, Span { file: 0, line: 3, column: 1, len: 3, expansion: 0 });
+    }
+
+    #[test]
+    fn errors_cut_the_line_short_and_lexing_goes_on() {
+        let mut errors = vec![];
+        let tokens = lex("LDA $1G\nLDA %12\nNOP", 0, &mut errors);
+        assert_eq!(errors, vec![
+            AssemblyError::BadNumber { text: "$1G".to_string(), span: Span { file: 0, line: 1, column: 5, len: 3, expansion: 0 } },
+            AssemblyError::BadNumber { text: "%12".to_string(), span: Span { file: 0, line: 2, column: 5, len: 3, expansion: 0 } },
+        ]);
+        let kinds: Vec<&TokenKind> = tokens.iter().map(|token| &token.kind).collect();
+        assert_eq!(kinds, vec![
+            &ident("LDA"),
+            &TokenKind::Newline,
+            &ident("LDA"),
+            &TokenKind::Newline,
+            &ident("NOP"),
+            &TokenKind::Newline,
+        ]);
+    }
+
+    #[test]
+    fn names_and_strings_keep_their_case() {
+        assert_eq!(kinds("lda Label"), vec![ident("lda"), ident("Label")]);
+        assert_eq!(kinds(".text \"Hi\""), vec![ident(".text"), TokenKind::Str(vec![Quoted::Char('H'), Quoted::Char('i')])]);
+    }
+
+    #[test]
+    fn quoted_escapes() {
+        let text = |line: &str| match kinds(line).remove(0) {
+            TokenKind::Str(text) => text,
+            other => panic!("expected a string but found {}", other),
+        };
+        let chars = |text: &str| text.chars().map(Quoted::Char).collect::<Vec<_>>();
+        assert_eq!(text(r#""a\n\r\t\0""#), chars("a\n\r\t\0"));
+        assert_eq!(text(r#""\\\"\'""#), chars("\\\"'"));
+        assert_eq!(text(r#""é\xE9""#), vec![Quoted::Char('é'), Quoted::Byte(0xe9)]);
+        assert_eq!(text(r#""it's""#), chars("it's"));
+    }
+
+    #[test]
+    fn bad_escapes() {
+        assert_eq!(error(r#""\q""#), "unknown escape sequence `\\q`");
+        assert_eq!(error(r#""\x4""#), "expected two hex digits after `\\x`");
+        assert_eq!(error(r#""\xG0""#), "expected two hex digits after `\\x`");
+        assert_eq!(error(r#""abc"#), "missing closing quote");
+        assert_eq!(error(r#""abc\"#), "missing closing quote");
+    }
+
+    #[test]
+    fn spans_count_characters() {
+        let tokens = lex(".BYTE \"é\", 1", 0, &mut vec![]);
+        assert_eq!(tokens[1].span, Span { file: 0, line: 1, column: 7, len: 3, expansion: 0 });
+        assert_eq!(tokens[3].span.column, 12);
+    }
+
+    #[test]
+    fn character_literals() {
+        assert_eq!(kinds("'a'"), vec![TokenKind::Char('a')]);
+        assert_eq!(kinds(r"'\''"), vec![TokenKind::Char('\'')]);
+        assert_eq!(kinds(r"'\n'"), vec![TokenKind::Char('\n')]);
+        assert_eq!(kinds(r"'\x41'"), vec![TokenKind::Number(0x41, AddressSize::U8)]);
+        assert_eq!(kinds("'a'%10")[1], TokenKind::Operator("%"));
+        assert_eq!(error("'ab'"), "character literals must contain a single character");
+        assert_eq!(error("''"), "character literals must contain a single character");
+    }
+}
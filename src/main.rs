@@ -1,11 +1,28 @@
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
-use std::error::Error;
 use std::fs;
-use std::cmp::Ordering;
 use std::fs::File;
-use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::io::{self, IsTerminal, Write};
+use std::process;
 
-use clap::{Arg, App};
+use clap::{value_t, App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use crate::charset::{Charset, Encoding};
+use crate::error::{AssemblyError, Expansion, SourceFile, Sources};
+use crate::expr::{BinaryOp, Context, Expr, ExprError};
+use crate::layout::{Segment, DEFAULT_SEGMENT};
+use crate::lexer::Span;
+use crate::object::{Check, Export, Field, Object, ObjectSegment, Relocation};
+use crate::parser::{Binary, DataWidth, MacroCall, Operand, Parser, Statement, StatementKind};
+
+mod charset;
+mod error;
+mod expr;
+mod layout;
+mod lexer;
+mod object;
+mod parser;
 
 /// Array of all opcodes in alphabetical order
 const OPS: [&str; 56] = [
@@ -91,35 +108,59 @@ enum AddressSize {
 
 /// Storage for defines to be processed in later pass
 struct Define {
-    size: AddressSize,
-    value: HexPair,
-}
-
-/// Storage for hex addresses
-struct HexPair {
-    /// The least-significant byte in an ordered byte pair
-    lower: u8,
-    /// The most-significant byte in an ordered byte pair
-    upper: u8,
+    /// The unevaluated value, which may refer to labels further down
+    value: Expr,
+    /// The statement the define is placed on like a label, whose address is
+    /// what `*` means in the value
+    line: usize,
+    span: Span,
 }
 
 /// Storage for labels to be processed in later pass.
-#[derive(Clone)]
+#[derive(Clone, Eq, PartialEq)]
 struct Label {
-    /// The string representation of a label
-    name: String,
     /// Value of location in memory of the first byte on the line with a label
     address: u16,
 }
 
+/// A single byte of machine code, operands are kept as expressions until
+/// every label has an address.
+enum Byte {
+    /// A byte that is already known, such as an opcode
+    Value(u8),
+    /// The operand of the instruction at `pc`, encoded according to its addressing mode
+    Operand { expr: Expr, mode: Addressing, pc: u16, span: Span },
+    /// A value from a data directive on the line starting at `pc`
+    Data { expr: Expr, width: DataWidth, pc: u16, span: Span },
+    /// `count` copies of a byte from a directive on the line starting at `pc`
+    Fill { expr: Expr, count: u16, pc: u16, span: Span },
+    /// Moves the location of the following bytes
+    Origin(u16),
+}
+
 /// Storage for final bytecode to be formatted, with exception of labels
 /// which are transformed in the final pass.
 struct MachineCode {
     /// Debug info to go at beginning of line in debug mode, split into lines in `Vec`
     debug_info: Vec<String>,
     /// Binary data split into `Vec`s for each line then each byte
-    binary_data: Vec<Vec<String>>,
-    defines: HashMap<String, Define>
+    binary_data: Vec<Vec<Byte>>,
+    defines: HashMap<String, Define>,
+    /// Checked once every label has its final address
+    assertions: Vec<Assertion>,
+    /// The segment each line is in, as an index into the layout
+    segments: Vec<usize>,
+    /// The address after the last byte placed in each segment
+    segment_ends: Vec<u32>,
+}
+
+/// A `.ASSERT` along with the address of the line it is on
+struct Assertion {
+    condition: Expr,
+    message: Option<String>,
+    segment: usize,
+    pc: u16,
+    span: Span,
 }
 
 impl MachineCode {
@@ -127,7 +168,10 @@ impl MachineCode {
         MachineCode {
             debug_info: vec![],
             binary_data: vec![],
-            defines: HashMap::new()
+            defines: HashMap::new(),
+            assertions: vec![],
+            segments: vec![],
+            segment_ends: vec![],
         }
     }
 
@@ -135,20 +179,147 @@ impl MachineCode {
         self.debug_info.insert(line, info);
     }
 
-    fn insert_byte(&mut self, line: usize, byte: String) {
+    fn insert_byte(&mut self, line: usize, byte: Byte) {
         match self.binary_data.get_mut(line) {
             Some(l) => l.push(byte),
             None => {
-                self.binary_data.insert(line, vec![]);
-                self.binary_data.get_mut(line).unwrap().push(byte);
+                self.binary_data.insert(line, vec![byte]);
             }
         }
 
     }
 }
 
-/// Currently unused, to be used to add more syntactic meaning to function calls
-#[allow(dead_code)]
+/// Resolves symbols while evaluating expressions, labels are only available
+/// once a layout pass has given them addresses.
+struct Symbols<'a> {
+    defines: &'a HashMap<String, Define>,
+    labels: Option<&'a HashMap<String, Label>>,
+    /// The address `*` stands for, which is the define's own while one is evaluated
+    pc: Cell<u16>,
+    /// Whether labels are offsets into segments that have no address yet, so
+    /// they can not be assumed to be in zero page
+    relocatable: bool,
+    /// Defines currently being evaluated, used to catch circular definitions
+    resolving: RefCell<Vec<String>>,
+}
+
+impl<'a> Symbols<'a> {
+    fn new(defines: &'a HashMap<String, Define>, labels: Option<&'a HashMap<String, Label>>, pc: u16) -> Symbols<'a> {
+        Symbols {
+            defines,
+            labels,
+            pc: Cell::new(pc),
+            relocatable: false,
+            resolving: RefCell::new(vec![]),
+        }
+    }
+
+    /// Runs `f` on a define at the address of its own line, once it has one,
+    /// while guarding against it referring to itself
+    fn with_define<T>(&self, name: &str, f: impl FnOnce(&Define) -> T) -> Option<Result<T, ExprError>> {
+        let define = self.defines.get(name)?;
+        if self.resolving.borrow().iter().any(|n| n == name) {
+            return Some(Err(ExprError::Circular(name.to_string())));
+        }
+        let pc = self.labels.and_then(|labels| labels.get(name)).map_or(self.pc.get(), |label| label.address);
+        let outer = self.pc.replace(pc);
+        self.resolving.borrow_mut().push(name.to_string());
+        let result = f(define);
+        self.resolving.borrow_mut().pop();
+        self.pc.set(outer);
+        Some(Ok(result))
+    }
+}
+
+impl<'a> Context for Symbols<'a> {
+    fn symbol(&self, name: &str) -> Result<i64, ExprError> {
+        if let Some(result) = self.with_define(name, |define| define.value.evaluate(self)) {
+            result?
+        } else if let Some(label) = self.labels.and_then(|labels| labels.get(name)) {
+            Ok(label.address as i64)
+        } else {
+            Err(ExprError::Undefined(name.to_string()))
+        }
+    }
+
+    fn symbol_size(&self, name: &str) -> AddressSize {
+        match self.with_define(name, |define| define.value.size(self)) {
+            Some(Ok(size)) => size,
+            Some(Err(_)) => AddressSize::Unknown,
+            None => match self.labels.and_then(|labels| labels.get(name)) {
+                Some(label) if label.address <= 0xff && !self.relocatable => AddressSize::U8,
+                Some(_) => AddressSize::U16,
+                None => AddressSize::Unknown,
+            },
+        }
+    }
+
+    fn pc(&self) -> i64 {
+        self.pc.get() as i64
+    }
+}
+
+/// Rewrites expressions for an object file, where labels are only known as
+/// an offset into their segment until the linker places it
+struct Relocatable<'a> {
+    defines: &'a HashMap<String, Define>,
+    /// The segment and offset of each label
+    labels: HashMap<String, (usize, u16)>,
+    imports: Vec<String>,
+}
+
+impl<'a> Relocatable<'a> {
+    /// Replaces defines with their values and labels with the address of their
+    /// segment plus an offset, leaving imports as they are. `*` is the line at
+    /// `pc` in `segment`.
+    fn rewrite(&self, expr: &Expr, segment: usize, pc: u16) -> Result<Expr, ExprError> {
+        self.rewrite_resolving(expr, segment, pc, &mut vec![])
+    }
+
+    /// Rewrites an expression inside the defines listed in `resolving`, used to
+    /// catch circular definitions
+    fn rewrite_resolving(&self, expr: &Expr, segment: usize, pc: u16, resolving: &mut Vec<String>) -> Result<Expr, ExprError> {
+        let address = |segment: usize, offset: u16| Expr::Binary(
+            BinaryOp::Add,
+            Box::new(Expr::Symbol(object::segment_symbol(segment))),
+            Box::new(Expr::Number(offset as i64, AddressSize::U16)),
+        );
+
+        Ok(match expr {
+            Expr::Number(..) | Expr::Char(_) => expr.clone(),
+            Expr::Pc => address(segment, pc),
+            Expr::Symbol(name) => {
+                if let Some(define) = self.defines.get(name) {
+                    if resolving.contains(name) {
+                        return Err(ExprError::Circular(name.clone()));
+                    }
+                    // `*` in a define is the address of its own line
+                    let (segment, pc) = self.labels.get(name).copied().unwrap_or((segment, pc));
+                    resolving.push(name.clone());
+                    let value = self.rewrite_resolving(&define.value, segment, pc, resolving);
+                    resolving.pop();
+                    value?
+                } else if let Some(&(segment, offset)) = self.labels.get(name) {
+                    address(segment, offset)
+                } else if self.imports.contains(name) {
+                    expr.clone()
+                } else {
+                    return Err(ExprError::Undefined(name.clone()));
+                }
+            }
+            Expr::Unary(op, e) => Expr::Unary(*op, Box::new(self.rewrite_resolving(e, segment, pc, resolving)?)),
+            Expr::Binary(op, l, r) => Expr::Binary(
+                *op,
+                Box::new(self.rewrite_resolving(l, segment, pc, resolving)?),
+                Box::new(self.rewrite_resolving(r, segment, pc, resolving)?),
+            ),
+        })
+    }
+}
+
+/// Addressing modes in the same order as the columns of `OPS_HEX`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 enum Addressing {
     Implied,
     Accumulator,
@@ -159,443 +330,1540 @@ enum Addressing {
     Zeropage,
     ZeropageXIndexed,
     ZeropageYIndexed,
-    IndirectYIndexed,
     Indirect,
+    XIndexedIndirect,
+    IndirectYIndexed,
     Relative,
 }
 
-/// Removes comments and tokenizes the imputed program as well as creating a
-/// map for tracking symbols.
+impl Addressing {
+    /// Every mode in column order
+    const ALL: [Addressing; 13] = [
+        Addressing::Implied,
+        Addressing::Accumulator,
+        Addressing::Immediate,
+        Addressing::Absolute,
+        Addressing::AbsoluteXIndexed,
+        Addressing::AbsoluteYIndexed,
+        Addressing::Zeropage,
+        Addressing::ZeropageXIndexed,
+        Addressing::ZeropageYIndexed,
+        Addressing::Indirect,
+        Addressing::XIndexedIndirect,
+        Addressing::IndirectYIndexed,
+        Addressing::Relative,
+    ];
+
+    /// Number of bytes following the opcode
+    fn operand_bytes(self) -> u16 {
+        match self {
+            Addressing::Implied | Addressing::Accumulator => 0,
+            Addressing::Absolute
+            | Addressing::AbsoluteXIndexed
+            | Addressing::AbsoluteYIndexed
+            | Addressing::Indirect => 2,
+            _ => 1,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Addressing::Implied => "implied",
+            Addressing::Accumulator => "accumulator",
+            Addressing::Immediate => "immediate",
+            Addressing::Absolute => "absolute",
+            Addressing::AbsoluteXIndexed => "absolute,X",
+            Addressing::AbsoluteYIndexed => "absolute,Y",
+            Addressing::Zeropage => "zero page",
+            Addressing::ZeropageXIndexed => "zero page,X",
+            Addressing::ZeropageYIndexed => "zero page,Y",
+            Addressing::Indirect => "indirect",
+            Addressing::XIndexedIndirect => "(indirect,X)",
+            Addressing::IndirectYIndexed => "(indirect),Y",
+            Addressing::Relative => "relative",
+        }
+    }
+
+    /// Returns if the opcode has an encoding for this mode
+    fn supported_by(self, op: usize) -> bool {
+        OPS_HEX[op][self as usize] != -1
+    }
+}
+
+/// Settings that change how a program is assembled
+#[derive(Clone)]
+struct Options {
+    debug: bool,
+    /// Whether only the bytes are output, without the origin markers
+    binary: bool,
+    /// Whether labels and defines differing only in case are different symbols
+    case_sensitive: bool,
+    /// Directories searched for included files after the directory of the
+    /// file including them
+    include_dirs: Vec<PathBuf>,
+    /// The areas of memory that segments are placed in, empty when no layout
+    /// is given
+    layout: Vec<Segment>,
+    /// Whether to output an object file to be linked with others, rather than
+    /// machine code
+    object: bool,
+}
+
+type Tokenized = (Vec<Statement>, HashMap<String, usize>, HashMap<String, Define>);
+
+/// Tokenizes and parses the imputed program, along with every file it
+/// includes and every macro it uses, as well as creating a map for tracking
+/// symbols. Lines with errors are left out and the errors recorded.
 fn create_symbols_and_tokenize(
-    code: &str,
-) -> (
-    Vec<Vec<String>>,
-    HashMap<String, usize>,
-    HashMap<String, Define>,
-) {
-    let mut labels = HashMap::new();
-    let mut defines = HashMap::new();
-    let mut tokens = Vec::new();
-
-    let mut solo_label = Vec::new();
-
-    let mut line_num = 0;
-
-
-    for line in code.lines() {
-        // Removes ; and splits into tokens
-        let split_comments: Vec<&str> = line.split(';').collect();
-        let split_tokens: Vec<&str> = split_comments[0].split_whitespace().collect();
-
-        if !split_tokens.is_empty() {
-            if split_tokens.iter().any(|s| s.contains('=')) {
-                let split_eq: Vec<&str> = split_comments[0].split('=').collect();
-                let address = address_size(split_eq[1].trim());
-                let value = match address {
-                    AddressSize::U8 => u8_decode(split_eq[1].trim()).unwrap(),
-                    AddressSize::U16 => u16_decode(split_eq[1].trim()).unwrap(),
-                    _ => panic!(),
+    sources: &mut Sources,
+    options: &Options,
+    errors: &mut Vec<AssemblyError>,
+) -> Tokenized {
+    let root = PathBuf::from(&sources.files[0].name);
+    let mut pass = FirstPass {
+        options,
+        sources,
+        errors,
+        labels: HashMap::new(),
+        defines: HashMap::new(),
+        tokens: Vec::new(),
+        solo_label: Vec::new(),
+        solo_defines: Vec::new(),
+        declared: HashMap::new(),
+        charset: Charset::new(Encoding::Ascii),
+        including: vec![fs::canonicalize(&root).unwrap_or(root)],
+        conditionals: Vec::new(),
+        macros: HashMap::new(),
+        recording: None,
+        expanding: Vec::new(),
+    };
+    pass.file(0);
+
+    // Labels and defines after the last statement mark the end of the program
+    let end = pass.solo_label.last().map(|&(_, span)| span)
+        .or_else(|| pass.solo_defines.last().map(|name| pass.defines[name].span));
+    if let Some(span) = end {
+        pass.place_solo_labels(None);
+        pass.tokens.push(Statement { label: None, kind: StatementKind::Empty, span });
+    }
+
+    (pass.tokens, pass.labels, pass.defines)
+}
+
+/// Everything collected while walking the statements of every file in order
+struct FirstPass<'a> {
+    options: &'a Options,
+    sources: &'a mut Sources,
+    errors: &'a mut Vec<AssemblyError>,
+    labels: HashMap<String, usize>,
+    defines: HashMap<String, Define>,
+    tokens: Vec<Statement>,
+    /// Labels on their own line, which belong to the next statement
+    solo_label: Vec<(String, Span)>,
+    /// Defines since the last statement, which are placed on the next one
+    solo_defines: Vec<String>,
+    /// Where each symbol was declared, for pointing out duplicates
+    declared: HashMap<String, Span>,
+    charset: Charset,
+    /// Canonical paths of the files currently being read, used to catch
+    /// files that end up including themselves
+    including: Vec<PathBuf>,
+    /// The `.IF` blocks that are open, innermost last
+    conditionals: Vec<Conditional>,
+    macros: HashMap<String, Macro>,
+    /// The macro or `.REPEAT` block whose lines are being kept until it is closed
+    recording: Option<Recording>,
+    /// The macros being expanded, innermost last, used to catch macros that
+    /// end up using themselves
+    expanding: Vec<String>,
+}
+
+/// The parameters and lines of a macro, which are assembled each time it is used
+#[derive(Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Statement>,
+    /// Where the macro was defined
+    span: Span,
+}
+
+/// Lines kept to be assembled later, up to the directive that closes them
+enum Recording {
+    Macro(String, Macro),
+    /// A `.REPEAT` block along with how many `.REPEAT` blocks inside it are open
+    Repeat { count: i64, var: Option<String>, body: Vec<Statement>, span: Span, depth: usize },
+}
+
+impl Recording {
+    fn body(&mut self) -> &mut Vec<Statement> {
+        match self {
+            Recording::Macro(_, definition) => &mut definition.body,
+            Recording::Repeat { body, .. } => body,
+        }
+    }
+}
+
+/// An open `.IF` block
+struct Conditional {
+    /// Where the block was opened, for reporting it if it is never closed
+    span: Span,
+    /// Whether the code around the block is being assembled
+    outer: bool,
+    /// Whether the current branch is being assembled
+    active: bool,
+    /// Whether any branch so far has been assembled
+    taken: bool,
+    /// Whether the block has reached its `.ELSE`
+    in_else: bool,
+}
+
+impl<'a> FirstPass<'a> {
+    /// Lexes and parses a source file then walks its statements
+    fn file(&mut self, file: usize) {
+        let code = self.sources.files[file].code.clone();
+        let reported = self.errors.len();
+        let lexed = lexer::lex(&code, file, self.errors);
+        let mut parser = Parser::new(&lexed, &code, self.options.case_sensitive);
+        let depth = self.conditionals.len();
+        // Lines in branches that are not assembled, which report no errors
+        let mut skipped = vec![];
+        loop {
+            // The macro being recorded counts so that it is caught using itself
+            let (macros, recording) = (&self.macros, &self.recording);
+            let is_macro = |name: &str| {
+                macros.contains_key(name) || matches!(recording, Some(Recording::Macro(n, _)) if n == name)
+            };
+            let skipping = recording.is_none() && !self.active();
+            let from = match parser.line() {
+                Some(line) => line,
+                None => break,
+            };
+            let statement = if skipping {
+                parser.next_block_directive(self.errors)
+            } else {
+                parser.next_statement(&is_macro, self.errors)
+            };
+
+            if skipping {
+                // The directive ending the branch counts when the block it
+                // belongs to is around code that is assembled
+                let ends_branch = |statement: &Statement| {
+                    matches!(statement.kind, StatementKind::ElseIf(_) | StatementKind::Else | StatementKind::EndIf)
+                        && self.conditionals.last().is_some_and(|conditional| conditional.outer)
+                };
+                let to = match &statement {
+                    Some(statement) if ends_branch(statement) => statement.span.line,
+                    Some(statement) => statement.span.line + 1,
+                    None => usize::MAX,
                 };
-                if split_eq[0].contains("*") {
-                    tokens.insert(line_num, vec!["*".to_string(), format!("{:02X} {:02X}", value.lower, value.upper).to_string()]);
-                    line_num += 1;
+                skipped.push(from..to);
+            }
+            match statement {
+                Some(statement) => self.statement(statement),
+                None => break,
+            }
+        }
+        let mut errors = self.errors.split_off(reported);
+        errors.retain(|e| {
+            let span = e.span();
+            span.file != file || span.expansion != 0 || !skipped.iter().any(|lines| lines.contains(&span.line))
+        });
+        self.errors.append(&mut errors);
+
+        // Blocks must be closed in the file they are opened in
+        self.close_blocks(depth);
+    }
+
+    /// Reports the block being recorded and the `.IF` blocks opened since
+    /// there were `depth` open
+    fn close_blocks(&mut self, depth: usize) {
+        for conditional in self.conditionals.drain(depth..) {
+            self.errors.push(AssemblyError::Unterminated { directive: ".IF", closer: ".ENDIF", span: conditional.span });
+        }
+        match self.recording.take() {
+            Some(Recording::Macro(_, definition)) => self.errors.push(AssemblyError::Unterminated {
+                directive: ".MACRO",
+                closer: ".ENDMACRO",
+                span: definition.span,
+            }),
+            Some(Recording::Repeat { span, .. }) => {
+                self.errors.push(AssemblyError::Unterminated { directive: ".REPEAT", closer: ".ENDREP", span })
+            }
+            None => {}
+        }
+    }
+
+    /// Returns if statements are being assembled rather than skipped by `.IF`
+    fn active(&self) -> bool {
+        self.conditionals.last().is_none_or(|conditional| conditional.active)
+    }
+
+    fn statement(&mut self, mut statement: Statement) {
+        if self.recording.is_some() {
+            return self.record(statement);
+        }
+
+        let outer = self.active();
+        if self.conditional(&statement) {
+            // A label on the line opening a block belongs to the code around it
+            let opens = matches!(statement.kind, StatementKind::If(_) | StatementKind::IfDef(_) | StatementKind::IfNDef(_));
+            if (opens && outer) || (!opens && self.active()) {
+                self.solo_label.extend(statement.label);
+            }
+            return;
+        }
+        if !self.active() {
+            return;
+        }
+
+        encode_chars(&mut statement, &self.charset, self.errors);
+        let span = statement.span;
+
+        match statement.kind {
+            StatementKind::Define(name, value) => {
+                if declare(&mut self.declared, &name, span, self.errors) {
+                    self.solo_defines.push(name.clone());
+                    self.defines.insert(name, Define { value, line: self.tokens.len(), span });
+                }
+            }
+            StatementKind::Empty => self.solo_label.extend(statement.label),
+            StatementKind::Charset(encoding) => {
+                self.charset = Charset::new(encoding);
+                self.solo_label.extend(statement.label);
+            }
+            StatementKind::CharMap(from, to) => {
+                // Only defines declared above can be used since the mapping
+                // applies from here on
+                let symbols = Symbols::new(&self.defines, None, 0);
+                let from = from.evaluate(&symbols)
+                    .map_err(|e| AssemblyError::from_expr(e, span))
+                    .and_then(|from| {
+                        std::char::from_u32(from as u32).filter(|_| from >= 0).ok_or(AssemblyError::Syntax {
+                            message: format!("{} is not a character", from),
+                            span,
+                        })
+                    });
+                let to = to.evaluate(&symbols)
+                    .map_err(|e| AssemblyError::from_expr(e, span))
+                    .and_then(|to| check_range(to, AddressSize::U8, span));
+                match (from, to) {
+                    (Ok(from), Ok(to)) => self.charset.remap(from, to as u8),
+                    (Err(e), _) | (_, Err(e)) => self.errors.push(e),
+                }
+                self.solo_label.extend(statement.label);
+            }
+            StatementKind::Include(ref path) => {
+                // Labels before an include belong to the first statement in it
+                self.solo_label.extend(statement.label.clone());
+                self.include(path, span);
+            }
+            StatementKind::Macro(name, params) => {
+                self.solo_label.extend(statement.label);
+                self.recording = Some(Recording::Macro(name, Macro { params, body: vec![], span }));
+            }
+            StatementKind::EndMacro => self.errors.push(AssemblyError::Unmatched {
+                directive: ".ENDMACRO".to_string(),
+                opener: ".MACRO",
+                span,
+            }),
+            StatementKind::MacroCall(call) => {
+                // Labels before a macro belong to the first statement it produces
+                self.solo_label.extend(statement.label);
+                self.expand(call, span);
+            }
+            StatementKind::Repeat(count, var) => {
+                self.solo_label.extend(statement.label);
+                // The count is needed now, so it can only use defines declared above
+                let count = count.evaluate(&Symbols::new(&self.defines, None, 0))
+                    .map_err(|e| AssemblyError::from_expr(e, span))
+                    .and_then(|count| match count {
+                        0..=0x10000 => Ok(count),
+                        _ => Err(AssemblyError::BadRepeat { count, span }),
+                    });
+                let count = count.unwrap_or_else(|e| {
+                    self.errors.push(e);
+                    0
+                });
+                self.recording = Some(Recording::Repeat { count, var, body: vec![], span, depth: 0 });
+            }
+            StatementKind::EndRepeat => self.errors.push(AssemblyError::Unmatched {
+                directive: ".ENDREP".to_string(),
+                opener: ".REPEAT",
+                span,
+            }),
+            StatementKind::Segment(ref name) => {
+                // Labels before a change of segment belong to the first line in it
+                self.solo_label.extend(statement.label.clone());
+                if self.options.object && name.contains(char::is_whitespace) {
+                    // The layout given to the linker could never name it
+                    self.errors.push(AssemblyError::Syntax {
+                        message: "segment names can not contain spaces".to_string(),
+                        span,
+                    });
+                } else if self.options.object || self.options.layout.iter().any(|area| &area.name == name) {
+                    self.tokens.push(statement);
                 } else {
-                    defines.insert(split_eq[0].trim().to_string(), Define { size: address, value });
+                    self.errors.push(AssemblyError::UnknownSegment {
+                        name: name.clone(),
+                        segments: self.options.layout.iter().map(|area| area.name.clone()).collect(),
+                        span,
+                    });
                 }
-            } else if split_tokens.len() == 1 && !OPS.contains(&split_tokens[0]) && split_tokens[0].ends_with(':') {
-                solo_label.push(split_tokens[0]);
-            } else {
-                tokens.insert(line_num, vec![]);
+            }
+            StatementKind::Import(ref names) => {
+                self.solo_label.extend(statement.label.clone());
+                if !self.options.object {
+                    return self.errors.push(AssemblyError::Syntax {
+                        message: "`.IMPORT` needs the program to be linked, assemble it with `--object`".to_string(),
+                        span,
+                    });
+                }
+                // Imports are declared so they can not also be labels or defines
+                for name in names {
+                    declare(&mut self.declared, name, span, self.errors);
+                }
+                self.tokens.push(statement);
+            }
+            StatementKind::Export(_) => {
+                // Without an object file there is nothing to export to
+                self.solo_label.extend(statement.label.clone());
+                if self.options.object {
+                    self.tokens.push(statement);
+                }
+            }
+            StatementKind::Origin(_) | StatementKind::Align(_) if self.options.object => {
+                let directive = if let StatementKind::Origin(_) = statement.kind { "ORG" } else { ".ALIGN" };
+                self.solo_label.extend(statement.label);
+                self.errors.push(AssemblyError::NotRelocatable { directive, span });
+            }
+            StatementKind::Origin(_) if !self.options.layout.is_empty() => {
+                self.solo_label.extend(statement.label);
+                self.errors.push(AssemblyError::OriginInLayout { span });
+            }
+            StatementKind::Error(message) => {
+                self.solo_label.extend(statement.label);
+                self.errors.push(AssemblyError::UserError { message, span });
+            }
+            StatementKind::Warning(message) => {
+                self.solo_label.extend(statement.label);
+                self.errors.push(AssemblyError::UserWarning { message, span });
+            }
+            StatementKind::IncludeBinary(ref mut binary) => {
+                match self.read_binary(binary, span) {
+                    Ok(bytes) => binary.bytes = bytes,
+                    Err(e) => self.errors.push(e),
+                }
+                self.place_solo_labels(statement.label.clone());
+                self.tokens.push(statement);
+            }
+            _ => {
+                self.place_solo_labels(statement.label.clone());
+                self.tokens.push(statement);
+            }
+        }
+    }
+
+    /// Keeps a line of the macro or `.REPEAT` block being recorded, or
+    /// finishes it at the directive closing it
+    fn record(&mut self, statement: Statement) {
+        let mut recording = match self.recording.take() {
+            Some(recording) => recording,
+            None => return,
+        };
 
-                for _ in 0..solo_label.len() {
-                    labels.insert(solo_label.pop().unwrap().trim_end_matches(':').to_string(), line_num);
+        let closes = match (&mut recording, &statement.kind) {
+            (_, StatementKind::Macro(..)) => {
+                self.errors.push(AssemblyError::Syntax {
+                    message: "macros can not be defined inside other macros or `.REPEAT` blocks".to_string(),
+                    span: statement.span,
+                });
+                self.recording = Some(recording);
+                return;
+            }
+            (Recording::Macro(..), StatementKind::EndMacro) => true,
+            (Recording::Repeat { depth: 0, .. }, StatementKind::EndRepeat) => true,
+            (Recording::Repeat { depth, .. }, StatementKind::Repeat(..)) => {
+                *depth += 1;
+                false
+            }
+            (Recording::Repeat { depth, .. }, StatementKind::EndRepeat) => {
+                *depth -= 1;
+                false
+            }
+            _ => false,
+        };
+
+        if closes {
+            if statement.label.is_some() {
+                recording.body().push(Statement { kind: StatementKind::Empty, ..statement });
+            }
+            self.finish(recording);
+        } else {
+            recording.body().push(statement);
+            self.recording = Some(recording);
+        }
+    }
+
+    /// Defines a macro once all of its lines are recorded, or assembles a
+    /// `.REPEAT` block once for each iteration
+    fn finish(&mut self, recording: Recording) {
+        match recording {
+            Recording::Macro(name, definition) => match self.macros.get(&name) {
+                Some(previous) => self.errors.push(AssemblyError::DuplicateSymbol {
+                    name,
+                    span: definition.span,
+                    previous: previous.span,
+                }),
+                None => {
+                    self.macros.insert(name, definition);
+                }
+            },
+            Recording::Repeat { count, var, body, span, .. } => {
+                for iteration in 0..count {
+                    let mut values = HashMap::new();
+                    if let Some(var) = &var {
+                        let size = if iteration <= 0xff { AddressSize::U8 } else { AddressSize::U16 };
+                        values.insert(var.clone(), Expr::Number(iteration, size));
+                    }
+                    self.replay(body.clone(), values, Expansion::Repeat { iteration, call: span });
                 }
+            }
+        }
+    }
 
-                let remove_label = if !OPS.contains(&split_tokens[0]) {
-                    labels.insert(split_tokens[0]
-                                      .trim_end_matches(':').to_string(), line_num);
-                    1
-                } else {
-                    0
-                };
+    /// Assembles the lines of a macro in place of the line using it, with the
+    /// parameters replaced by the arguments
+    fn expand(&mut self, call: MacroCall, span: Span) {
+        let definition = match self.macros.get(&call.name) {
+            Some(definition) => definition.clone(),
+            None => return self.errors.push(AssemblyError::UnknownMnemonic { name: call.name, span }),
+        };
+        if self.expanding.contains(&call.name) {
+            return self.errors.push(AssemblyError::RecursiveMacro { name: call.name, span });
+        }
+        let values = match bind_arguments(&call, &definition, span) {
+            Ok(values) => values,
+            Err(errors) => return self.errors.extend(errors),
+        };
+
+        self.expanding.push(call.name.clone());
+        self.replay(definition.body, values, Expansion::Macro { name: call.name, call: span });
+        self.expanding.pop();
+    }
+
+    /// Assembles recorded lines with symbols replaced by `values`. Labels and
+    /// defines declared in the lines are renamed to `NAME@n` so that each
+    /// expansion has its own.
+    fn replay(&mut self, body: Vec<Statement>, mut values: HashMap<String, Expr>, expansion: Expansion) {
+        self.sources.expansions.push(expansion);
+        let expansion = self.sources.expansions.len();
 
-                for token in split_tokens.iter().skip(remove_label) {
-                    tokens.get_mut(line_num).unwrap().push((*token).to_string())
+        let mut locals = HashMap::new();
+        for statement in &body {
+            let defined = match &statement.kind {
+                StatementKind::Define(name, _) => Some(name),
+                _ => None,
+            };
+            for name in statement.label.iter().map(|(name, _)| name).chain(defined) {
+                locals.insert(name.clone(), format!("{}@{}", name, expansion));
+            }
+        }
+        for (name, local) in &locals {
+            values.entry(name.clone()).or_insert_with(|| Expr::Symbol(local.clone()));
+        }
+        let rename = |name: &mut String| {
+            if let Some(local) = locals.get(name) {
+                *name = local.clone();
+            }
+        };
+
+        // The counters of `.REPEAT` blocks inside the lines hide any value of
+        // the same name until the block ends
+        let mut hidden: Vec<Option<String>> = vec![];
+        let depth = self.conditionals.len();
+        for mut statement in body {
+            if let Some((name, _)) = &mut statement.label {
+                rename(name);
+            }
+            match &mut statement.kind {
+                StatementKind::Define(name, _) | StatementKind::IfDef(name) | StatementKind::IfNDef(name) => rename(name),
+                _ => {}
+            }
+
+            let scoped: HashMap<String, Expr>;
+            let visible = if hidden.iter().flatten().any(|name| values.contains_key(name)) {
+                scoped = values.iter()
+                    .filter(|(name, _)| !hidden.iter().flatten().any(|hidden| hidden == *name))
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect();
+                &scoped
+            } else {
+                &values
+            };
+            for (expr, _) in statement.exprs_mut() {
+                expr.substitute(visible);
+            }
+            match &statement.kind {
+                StatementKind::Repeat(_, var) => hidden.push(var.clone()),
+                StatementKind::EndRepeat => {
+                    hidden.pop();
+                }
+                _ => {}
+            }
+
+            for span in statement.spans_mut() {
+                span.expansion = expansion;
+            }
+            self.statement(statement);
+        }
+
+        // Blocks must be closed in the lines they are opened in
+        self.close_blocks(depth);
+    }
+
+    /// Opens, continues or closes an `.IF` block, returning false if the
+    /// statement is not part of one
+    fn conditional(&mut self, statement: &Statement) -> bool {
+        let span = statement.span;
+        let unmatched = |directive: &str| AssemblyError::Unmatched { directive: directive.to_string(), opener: ".IF", span };
+
+        match &statement.kind {
+            StatementKind::If(_) | StatementKind::IfDef(_) | StatementKind::IfNDef(_) => {
+                let outer = self.active();
+                // Conditions inside skipped code are not evaluated
+                let condition = outer && self.condition(&statement.kind, span);
+                self.conditionals.push(Conditional { span, outer, active: condition, taken: condition, in_else: false });
+            }
+            StatementKind::ElseIf(_) => match self.conditionals.last() {
+                None => self.errors.push(unmatched(".ELSEIF")),
+                Some(conditional) if conditional.in_else => self.errors.push(AssemblyError::Syntax {
+                    message: "`.ELSEIF` can not come after `.ELSE`".to_string(),
+                    span,
+                }),
+                Some(conditional) => {
+                    let condition = conditional.outer && !conditional.taken && self.condition(&statement.kind, span);
+                    if let Some(conditional) = self.conditionals.last_mut() {
+                        conditional.active = condition;
+                        conditional.taken |= condition;
+                    }
+                }
+            },
+            StatementKind::Else => match self.conditionals.last_mut() {
+                None => self.errors.push(unmatched(".ELSE")),
+                Some(conditional) if conditional.in_else => self.errors.push(AssemblyError::Syntax {
+                    message: "`.ELSE` can not come after `.ELSE`".to_string(),
+                    span,
+                }),
+                Some(conditional) => {
+                    conditional.active = conditional.outer && !conditional.taken;
+                    conditional.taken = true;
+                    conditional.in_else = true;
+                }
+            },
+            StatementKind::EndIf => {
+                if self.conditionals.pop().is_none() {
+                    self.errors.push(unmatched(".ENDIF"));
                 }
-                line_num += 1;
             }
+            _ => return false,
         }
+        true
     }
 
-    (tokens, labels, defines)
+    /// Evaluates the condition of an `.IF`, `.IFDEF` or `.IFNDEF`, which can
+    /// only use symbols declared above
+    fn condition(&mut self, kind: &StatementKind, span: Span) -> bool {
+        match kind {
+            StatementKind::IfDef(name) => self.declared.contains_key(name),
+            StatementKind::IfNDef(name) => !self.declared.contains_key(name),
+            StatementKind::If(expr) | StatementKind::ElseIf(expr) => {
+                match expr.evaluate(&Symbols::new(&self.defines, None, 0)) {
+                    Ok(value) => value != 0,
+                    Err(e) => {
+                        self.errors.push(AssemblyError::from_expr(e, span));
+                        false
+                    }
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Gives the waiting labels, and the label of the statement about to be
+    /// added, the position of that statement
+    fn place_solo_labels(&mut self, label: Option<(String, Span)>) {
+        let labels: Vec<_> = self.solo_label.drain(..).chain(label).collect();
+        for (label, span) in labels {
+            if declare(&mut self.declared, &label, span, self.errors) {
+                self.labels.insert(label, self.tokens.len());
+            }
+        }
+        for name in self.solo_defines.drain(..) {
+            if let Some(define) = self.defines.get_mut(&name) {
+                define.line = self.tokens.len();
+            }
+        }
+    }
+
+    /// Reads an included file and walks its statements as if they were written
+    /// in place of the include
+    fn include(&mut self, path: &str, span: Span) {
+        let resolved = match self.resolve(path, span) {
+            Ok(resolved) => resolved,
+            Err(e) => return self.errors.push(e),
+        };
+
+        let canonical = fs::canonicalize(&resolved).unwrap_or_else(|_| resolved.clone());
+        if self.including.contains(&canonical) {
+            return self.errors.push(AssemblyError::CircularInclude { path: path.to_string(), span });
+        }
+
+        let code = match fs::read_to_string(&resolved) {
+            Ok(code) => code,
+            Err(e) => {
+                return self.errors.push(AssemblyError::FileError {
+                    path: path.to_string(),
+                    message: e.to_string(),
+                    span,
+                })
+            }
+        };
+        self.sources.files.push(SourceFile { name: resolved.display().to_string(), code });
+
+        self.including.push(canonical);
+        self.file(self.sources.files.len() - 1);
+        self.including.pop();
+    }
+
+    /// Reads the part of a file asked for by `.INCBIN`, the offset and length
+    /// can only use defines declared above
+    fn read_binary(&self, binary: &Binary, span: Span) -> Result<Vec<u8>, AssemblyError> {
+        let resolved = self.resolve(&binary.path, span)?;
+        let bytes = fs::read(&resolved).map_err(|e| AssemblyError::FileError {
+            path: binary.path.clone(),
+            message: e.to_string(),
+            span,
+        })?;
+
+        let symbols = Symbols::new(&self.defines, None, 0);
+        let evaluate = |value: &Option<(Expr, Span)>| match value {
+            Some((expr, span)) => expr.evaluate(&symbols).map(Some).map_err(|e| AssemblyError::from_expr(e, *span)),
+            None => Ok(None),
+        };
+        let start = evaluate(&binary.offset)?.unwrap_or(0);
+        let end = evaluate(&binary.length)?.map_or(bytes.len() as i64, |length| start + length);
+
+        if start < 0 || end < start || end > bytes.len() as i64 {
+            let span = match (&binary.offset, &binary.length) {
+                (Some((_, offset)), Some((_, length))) => offset.to(*length),
+                (Some((_, offset)), None) => *offset,
+                _ => span,
+            };
+            return Err(AssemblyError::BinaryRange { path: binary.path.clone(), start, end, size: bytes.len(), span });
+        }
+        Ok(bytes[start as usize..end as usize].to_vec())
+    }
+
+    /// Finds a file next to the file referring to it, or otherwise in the
+    /// first include directory that has it
+    fn resolve(&self, path: &str, span: Span) -> Result<PathBuf, AssemblyError> {
+        let dir = Path::new(&self.sources.files[span.file].name).parent().map(Path::to_path_buf).unwrap_or_default();
+        std::iter::once(&dir)
+            .chain(&self.options.include_dirs)
+            .map(|dir| dir.join(path))
+            .find(|candidate| candidate.is_file())
+            .ok_or_else(|| AssemblyError::FileNotFound { path: path.to_string(), span })
+    }
 }
 
+/// Matches the arguments of a macro to its parameters, returning the value of
+/// each parameter or every mismatch
+fn bind_arguments(call: &MacroCall, definition: &Macro, span: Span) -> Result<HashMap<String, Expr>, Vec<AssemblyError>> {
+    let bad = |message: String, span: Span| AssemblyError::BadArguments { message, span, definition: definition.span };
+    let mut values = HashMap::new();
+    let mut errors = vec![];
+
+    for (index, arg) in call.args.iter().enumerate() {
+        let param = match &arg.name {
+            Some((name, name_span)) if !definition.params.contains(name) => {
+                errors.push(bad(format!("macro `{}` has no parameter `{}`", call.name, name), *name_span));
+                continue;
+            }
+            Some((name, _)) => name,
+            None => match definition.params.get(index) {
+                Some(param) => param,
+                None => {
+                    let count = definition.params.len();
+                    let message = format!(
+                        "macro `{}` takes {} argument{} but {} were given",
+                        call.name, count, if count == 1 { "" } else { "s" }, call.args.len()
+                    );
+                    errors.push(bad(message, arg.span));
+                    break;
+                }
+            },
+        };
+        if values.insert(param.clone(), arg.value.clone()).is_some() {
+            errors.push(bad(format!("`{}` is given more than once", param), arg.span));
+        }
+    }
+
+    for param in &definition.params {
+        if !values.contains_key(param) && errors.is_empty() {
+            errors.push(bad(format!("missing an argument for `{}`", param), span));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(values)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Records where a symbol is declared, returning false and reporting it if it
+/// already was
+fn declare(declared: &mut HashMap<String, Span>, name: &str, span: Span, errors: &mut Vec<AssemblyError>) -> bool {
+    match declared.get(name) {
+        Some(previous) => {
+            errors.push(AssemblyError::DuplicateSymbol { name: name.to_string(), span, previous: *previous });
+            false
+        }
+        None => {
+            declared.insert(name.to_string(), span);
+            true
+        }
+    }
+}
+
+/// Replaces the character literals in a statement with their codes in the
+/// current character set
+fn encode_chars(statement: &mut Statement, charset: &Charset, errors: &mut Vec<AssemblyError>) {
+    // The character being mapped is kept as it was written
+    if let StatementKind::CharMap(..) = statement.kind {
+        return;
+    }
+
+    for (expr, span) in statement.exprs_mut() {
+        if let Err(c) = expr.encode_chars(&|c| charset.encode(c)) {
+            errors.push(AssemblyError::UnmappedChar { c, charset: charset.encoding.name(), span });
+            // Stops the same character being reported as out of range later
+            *expr = Expr::Number(0, AddressSize::U8);
+        }
+    }
+}
+
+/// Number of layout passes that may shrink an operand to zero page, after this
+/// operands can only grow so that the layout is guaranteed to settle.
+const SHRINK_PASSES: usize = 8;
+
+/// Number of layout passes after which the layout is taken to never settle.
+/// Operands can only grow once each after `SHRINK_PASSES`, so what keeps labels
+/// moving by then is a `.RES` or `.ALIGN` that changes size or an `ORG` that
+/// changes address.
+const MAX_PASSES: usize = 64;
+
 /// Takes tokens and symbols and outputs the hex machine code. Has an option for
 /// a debug mode which prints a verbose that shows all information needed for
 /// human legibility.
+///
+/// The size of an operand depends on the addresses of labels, which depend on
+/// the size of every operand before them, so the layout is repeated using the
+/// label addresses from the previous pass until they stop changing. Operands
+/// referring to labels that have not been placed yet start out as absolute.
 fn tokens_to_machine_code(
-    tokens: &[Vec<String>],
+    tokens: &[Statement],
+    labels: &HashMap<String, usize>,
+    defines: &HashMap<String, Define>,
+    options: &Options,
+    errors: &mut Vec<AssemblyError>,
+) -> (MachineCode, HashMap<String, Label>) {
+    let mut known = None;
+    let mut floor = vec![];
+    let mut previous: Option<MachineCode> = None;
+    let mut pass = 0;
+
+    loop {
+        pass += 1;
+        let mut pass_errors = vec![];
+        let (machine_code, label_locations, sizes) =
+            layout_pass(tokens, labels, defines, options, known.as_ref(), &floor, &mut pass_errors);
+
+        if known.as_ref() == Some(&label_locations) {
+            errors.extend(pass_errors);
+            return (machine_code, label_locations);
+        }
+        if pass >= MAX_PASSES {
+            if let Some(previous) = &previous {
+                errors.extend(unsettled(tokens, previous, &machine_code));
+            }
+            return (machine_code, label_locations);
+        }
+        if pass >= SHRINK_PASSES {
+            floor = sizes;
+        }
+        known = Some(label_locations);
+        previous = Some(machine_code);
+    }
+}
+
+/// Reports each `.RES` and `.ALIGN` that reserved a different number of bytes
+/// and each `ORG` that moved to a different address than in the pass before
+fn unsettled(tokens: &[Statement], previous: &MachineCode, current: &MachineCode) -> Vec<AssemblyError> {
+    let moved = |code: &MachineCode, line_num: usize| {
+        code.binary_data.get(line_num)?.iter().find_map(|byte| match byte {
+            Byte::Fill { count, .. } => Some(*count),
+            Byte::Origin(address) => Some(*address),
+            _ => None,
+        })
+    };
+    tokens.iter().enumerate()
+        .filter(|&(line_num, _)| moved(previous, line_num) != moved(current, line_num))
+        .filter_map(|(_, line)| match &line.kind {
+            StatementKind::Reserve(space) | StatementKind::Align(space) => Some(AssemblyError::Unsettled {
+                directive: space.directive.clone(),
+                span: space.size.1,
+            }),
+            StatementKind::Origin(_) => Some(AssemblyError::Unsettled { directive: "ORG".to_string(), span: line.span }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Lays out every statement once using the label addresses from the previous
+/// pass, returning the machine code, where each label ended up and the operand
+/// size chosen for each statement. Statements with errors are skipped but
+/// still take up space so the rest of the program keeps its layout. Each
+/// segment in the layout continues from where it was left.
+fn layout_pass(
+    tokens: &[Statement],
     labels: &HashMap<String, usize>,
     defines: &HashMap<String, Define>,
-) -> Result<(MachineCode, HashMap<String, Label>), Box<dyn Error>> {
+    options: &Options,
+    known: Option<&HashMap<String, Label>>,
+    floor: &[AddressSize],
+    errors: &mut Vec<AssemblyError>,
+) -> (MachineCode, HashMap<String, Label>, Vec<AddressSize>) {
     let mut machine_code = MachineCode::new(0);
     let mut label_locations = HashMap::new();
-    let mut byte_num = 0;
+    let mut sizes = vec![AddressSize::U8; tokens.len()];
+    let layout = &options.layout;
+    // Lines before the first `.SEGMENT` go in `CODE`, and need a `.SEGMENT`
+    // when the layout has no such segment
+    let default = layout.iter().position(|area| area.name == DEFAULT_SEGMENT);
+    let mut segment = default.unwrap_or(0);
+    let mut placed = layout.is_empty() || default.is_some();
+    let mut byte_num = layout.get(segment).map_or(0, |area| area.start);
+    let mut pcs: Vec<u16> = layout.iter().map(|area| area.start).collect();
+    let mut overflows: Vec<Option<Span>> = vec![None; layout.len()];
+    // Whether the last line ended right at the end of memory, so that the
+    // next one starts at `$10000` rather than the `$0000` the address wraps to
+    let mut at_end = false;
+    machine_code.segment_ends = layout.iter().map(|area| area.start as u32).collect();
 
     for (line_num, line) in tokens.iter().enumerate() {
+        let symbols = Symbols { relocatable: options.object, ..Symbols::new(defines, known, byte_num) };
+        let line_start = byte_num;
+        machine_code.segments.push(segment);
 
-        if line[0].as_str() == "*" || line[0].as_str() == "ORG" {
-            // Set Location
-            machine_code.insert_byte(line_num, "*".parse().unwrap());
-            machine_code.insert_byte(line_num, (&line[1]).parse().unwrap());
-            let val = u16_decode(&line[1]).unwrap();
-            byte_num = (val.lower as u16) + (val.upper as u16) * 0x100;
-        } else {
-            let op_name = line[0].clone();
-
-            let op = OPS.iter().position(|&s| s == op_name).unwrap_or_else(|| panic!(format!("Unknow opcode: {}", op_name)));
+        match &line.kind {
+            StatementKind::Segment(name) => {
+                if let Some(index) = layout.iter().position(|area| &area.name == name) {
+                    if placed {
+                        pcs[segment] = byte_num;
+                    }
+                    placed = true;
+                    segment = index;
+                    byte_num = pcs[index];
+                }
+                machine_code.insert_debug_info(line_num, String::new());
+                machine_code.binary_data.insert(line_num, vec![]);
+            }
+            StatementKind::Origin(expr) => {
+                // Set Location
+                let address = expr.evaluate(&symbols)
+                    .map_err(|e| AssemblyError::from_expr(e, line.span))
+                    .and_then(|address| check_range(address, AddressSize::U16, line.span));
+                match address {
+                    Ok(address) => byte_num = address as u16,
+                    Err(e) => errors.push(e),
+                }
+                machine_code.insert_debug_info(line_num, String::new());
+                machine_code.insert_byte(line_num, Byte::Origin(byte_num));
+                place_labels(labels, defines, line_num, byte_num, &mut label_locations);
+            }
+            StatementKind::Data(data) => {
+                let sym = place_labels(labels, defines, line_num, byte_num, &mut label_locations);
+                machine_code.insert_debug_info(line_num,
+                                               format!("{:<04X} {:<06} {:<03} {:<012} ",
+                                                       &byte_num, &sym, &data.directive, &data.text)
+                );
+                machine_code.binary_data.insert(line_num, vec![]);
 
-            let mut sym = "".to_string();
+                let pc = byte_num;
+                for (expr, span) in &data.items {
+                    let width = data.width;
+                    machine_code.insert_byte(line_num, Byte::Data { expr: expr.clone(), width, pc, span: *span });
+                    byte_num = byte_num.wrapping_add(width.bytes());
+                }
+            }
+            StatementKind::IncludeBinary(binary) => {
+                let sym = place_labels(labels, defines, line_num, byte_num, &mut label_locations);
+                machine_code.insert_debug_info(line_num,
+                                               format!("{:<04X} {:<06} {:<03} {:<012} ",
+                                                       &byte_num, &sym, ".INCBIN", &binary.text)
+                );
+                machine_code.binary_data.insert(line_num, vec![]);
 
-            for (label, label_line) in labels {
-                if line_num == *label_line {
-                    sym = label.clone();
-                    let l = Label {
-                        name: label.clone(),
-                        address: byte_num,
-                    };
-                    label_locations.insert(label.clone(), l);
+                match check_count(binary.bytes.len() as i64, byte_num, line.span) {
+                    Ok(count) => {
+                        for byte in &binary.bytes {
+                            machine_code.insert_byte(line_num, Byte::Value(*byte));
+                        }
+                        byte_num = byte_num.wrapping_add(count);
+                    }
+                    Err(e) => errors.push(e),
                 }
             }
+            StatementKind::Empty => {
+                place_labels(labels, defines, line_num, byte_num, &mut label_locations);
+                machine_code.insert_debug_info(line_num, String::new());
+                machine_code.binary_data.insert(line_num, vec![]);
+            }
+            StatementKind::Assert(condition, message) => {
+                place_labels(labels, defines, line_num, byte_num, &mut label_locations);
+                machine_code.insert_debug_info(line_num, String::new());
+                machine_code.binary_data.insert(line_num, vec![]);
+                machine_code.assertions.push(Assertion {
+                    condition: condition.clone(),
+                    message: message.clone(),
+                    segment,
+                    pc: byte_num,
+                    span: line.span,
+                });
+            }
+            StatementKind::Reserve(space) | StatementKind::Align(space) => {
+                let sym = place_labels(labels, defines, line_num, byte_num, &mut label_locations);
+                machine_code.insert_debug_info(line_num,
+                                               format!("{:<04X} {:<06} {:<03} {:<012} ",
+                                                       &byte_num, &sym, &space.directive, &space.text)
+                );
+                machine_code.binary_data.insert(line_num, vec![]);
 
-            machine_code.insert_debug_info(line_num,
-                                           format!("{:<04X} {:<06} {:<03} {:<012} ",
-                                                   &byte_num, &sym, &op_name, line.get(1).unwrap_or(&"".to_string())).to_string()
-            );
+                // The size is needed now to place everything after it, so it
+                // can only refer to labels placed in the previous pass
+                let (size, span) = &space.size;
+                let count = size.evaluate(&symbols)
+                    .map_err(|e| AssemblyError::from_expr(e, *span))
+                    .and_then(|size| match line.kind {
+                        StatementKind::Align(_) if !(1..=0x10000).contains(&size) => {
+                            Err(AssemblyError::BadAlignment { boundary: size, span: *span })
+                        }
+                        // Pads up to the next multiple of the boundary
+                        StatementKind::Align(_) => Ok((size - byte_num as i64 % size) % size),
+                        _ => Ok(size),
+                    })
+                    .and_then(|count| check_count(count, byte_num, *span));
+                let count = match count {
+                    Ok(count) => count,
+                    Err(e) => {
+                        errors.push(e);
+                        continue;
+                    }
+                };
 
-            if line.len() == 1 {
-                machine_code.insert_byte(line_num, format_opcode(op, 0));
-            } else if line.len() == 2 {
-                if line[1].as_str() == "A" {
-                    // Accumulator Mode
-                    machine_code.insert_byte(line_num, format_opcode(op, 1));
-                } else {
-                    let address = line[1]
-                        .trim_end_matches(",X")
-                        .trim_end_matches(",Y")
-                        .trim_end_matches(')')
-                        .trim_end_matches(",X")
-                        .trim_start_matches('(')
-                        .trim_start_matches('#');
-
-                    let address_str = address_to_string(address, &labels, &defines);
-                    let mut num_bit = address_size(address);
-
-                    if labels.contains_key(address) {
-                        num_bit = if &op_name == "JMP" || &op_name == "JSR" {
-                            AddressSize::U16
+                let (expr, span) = space.fill.clone().unwrap_or((Expr::Number(0, AddressSize::U8), *span));
+                machine_code.insert_byte(line_num, Byte::Fill { expr, count, pc: byte_num, span });
+                byte_num = byte_num.wrapping_add(count);
+            }
+            StatementKind::Instruction(instruction) => {
+                let op_name = &instruction.mnemonic;
+
+                let op = match OPS.iter().position(|s| s == op_name) {
+                    Some(op) => op,
+                    None => {
+                        errors.push(AssemblyError::UnknownMnemonic { name: op_name.clone(), span: line.span });
+                        machine_code.insert_debug_info(line_num, String::new());
+                        machine_code.binary_data.insert(line_num, vec![]);
+                        continue;
+                    }
+                };
+
+                let sym = place_labels(labels, defines, line_num, byte_num, &mut label_locations);
+
+                machine_code.insert_debug_info(line_num,
+                                               format!("{:<04X} {:<06} {:<03} {:<012} ",
+                                                       &byte_num, &sym, op_name, &instruction.text)
+                );
+
+                let operand = &instruction.operand;
+                // Bytes are only added once the opcode is known to be valid
+                machine_code.binary_data.insert(line_num, vec![]);
+
+                match operand.expr() {
+                    None => {
+                        // Accumulator Mode can be written without the `A`
+                        let mode = if matches!(operand, Operand::Accumulator) || !Addressing::Implied.supported_by(op) {
+                            Addressing::Accumulator
                         } else {
-                            AddressSize::U8
+                            Addressing::Implied
                         };
-                    } else if defines.contains_key(address.trim_start_matches('<').trim_start_matches('>')) {
-                        num_bit = if address.starts_with('<') || address.starts_with('>') {
-                            AddressSize::U8
+                        let opcode = if matches!(operand, Operand::Implied) && !mode.supported_by(op) {
+                            Err(AssemblyError::MissingOperand { mnemonic: op_name.clone(), span: line.span })
                         } else {
-                            defines.get(address).unwrap().size
+                            format_opcode(op, mode, line.span)
+                        };
+                        match opcode {
+                            Ok(byte) => machine_code.insert_byte(line_num, byte),
+                            Err(e) => errors.push(e),
                         }
                     }
+                    Some(expr) => {
+                        let mut num_bit = expr.size(&symbols);
 
-                    match num_bit {
-                        AddressSize::U8 => byte_num += 1,
-                        AddressSize::U16 => byte_num += 2,
-                        _ => {}
-                    }
-
-                    if line[1].starts_with('#') {
-                        // Immediate Mode
-                        machine_code.insert_byte(line_num, format_opcode(op, 2));
-                    } else if line[1].starts_with('(') {
-                        //Indirects
-                        if line[1].ends_with(",X)") {
-                            // Indexed Indirect
-                            machine_code.insert_byte(line_num, format_opcode(op, 10));
-                        } else if line[1].ends_with("),Y") {
-                            // Indirect Indexed
-                            machine_code.insert_byte(line_num, format_opcode(op, 11));
-                        } else if line[1].ends_with(')') {
-                            // Indirect
-                            machine_code.insert_byte(line_num, format_opcode(op, 9));
+                        if let Some(forced) = instruction.size {
+                            num_bit = forced;
                         } else {
-                            panic!("Unknown pattern: Line starts with '(' but does not end");
-                        }
-                    } else if line[1].ends_with(",X") {
-                        // X-Indexed
-                        match num_bit {
-                            AddressSize::U8 => {
-                                // Zero-page
-                                machine_code.insert_byte(line_num, format_opcode(op, 7));
+                            if num_bit == AddressSize::Unknown {
+                                // Refers to a label that has not been placed yet
+                                num_bit = AddressSize::U16;
                             }
-                            AddressSize::U16 => {
-                                // Absolute
-                                machine_code.insert_byte(line_num, format_opcode(op, 4));
+                            if let Some(&min) = floor.get(line_num) {
+                                num_bit = num_bit.max(min);
                             }
-                            _ => panic!(),
                         }
-                    } else if line[1].ends_with(",Y") {
-                        // Y-Indexed
-                        match num_bit {
-                            AddressSize::U8 => {
-                                // Zero-page
-                                machine_code.insert_byte(line_num, format_opcode(op, 8));
-                            }
-                            AddressSize::U16 => {
-                                // Absolute
-                                machine_code.insert_byte(line_num, format_opcode(op, 5));
+
+                        // Zero page is used when the address fits and the opcode has it
+                        let zeropage = |mode: Addressing| num_bit == AddressSize::U8 && mode.supported_by(op);
+                        let mode = match operand {
+                            Operand::Immediate(_) => Addressing::Immediate,
+                            Operand::Indirect(_) => Addressing::Indirect,
+                            Operand::IndexedIndirect(_) => Addressing::XIndexedIndirect,
+                            Operand::IndirectIndexed(_) => Addressing::IndirectYIndexed,
+                            Operand::XIndexed(_) if zeropage(Addressing::ZeropageXIndexed) => Addressing::ZeropageXIndexed,
+                            Operand::XIndexed(_) => Addressing::AbsoluteXIndexed,
+                            Operand::YIndexed(_) if zeropage(Addressing::ZeropageYIndexed) => Addressing::ZeropageYIndexed,
+                            Operand::YIndexed(_) => Addressing::AbsoluteYIndexed,
+                            _ if Addressing::Relative.supported_by(op) => Addressing::Relative,
+                            _ if zeropage(Addressing::Zeropage) => Addressing::Zeropage,
+                            _ => Addressing::Absolute,
+                        };
+
+                        let expr = expr.clone();
+                        let span = instruction.operand_span;
+                        let bytes = if num_bit == AddressSize::U8 { 1 } else { 2 };
+                        let opcode = if instruction.size.is_some() && mode.operand_bytes() != bytes {
+                            Err(AssemblyError::ForcedSize { mnemonic: op_name.clone(), mode, bytes, span: line.span })
+                        } else {
+                            format_opcode(op, mode, span)
+                        };
+                        match opcode {
+                            Ok(byte) => {
+                                machine_code.insert_byte(line_num, byte);
+                                machine_code.insert_byte(line_num, Byte::Operand { expr, mode, pc: byte_num, span });
                             }
-                            _ => panic!(),
-                        }
-                    } else if num_bit == AddressSize::U8 {
-                        if OPS_HEX[op][12] != -1 {
-                            //Relative
-                            machine_code.insert_byte(line_num, format_opcode(op, 12));
-                        } else if OPS_HEX[op][6] != -1 {
-                            //Zeropage
-                            machine_code.insert_byte(line_num, format_opcode(op, 6));
-                        } else if OPS_HEX[op][3] != -1 {
-                            //Absolute with no high bytes
-                            machine_code.insert_byte(line_num, format_opcode(op, 3));
-                            byte_num += 1;
-                            machine_code.insert_byte(line_num, (&address_str).parse().unwrap());
-                            machine_code.insert_byte(line_num, "00".parse().unwrap());
+                            Err(e) => errors.push(e),
                         }
-                    } else if num_bit == AddressSize::U16 {
-                        //Absolute
-                        machine_code.insert_byte(line_num, format_opcode(op, 3));
-                    } else {
-                        panic!(format!("Op: {} Addr: {}, Size: {:?}", &op_name, &address, &num_bit))
-                    }
-                    match machine_code.binary_data.get(line_num) {
-                        Some(s) => {
-                            if s.len() <= 2 {
-                                machine_code.insert_byte(line_num, address_str);
-                            }
-                        },
-                        None => machine_code.insert_byte(line_num, address_str)
+
+                        sizes[line_num] = num_bit;
+                        byte_num = byte_num.wrapping_add(mode.operand_bytes());
                     }
                 }
-            } else if line[1].len() > 2 {
-                panic!("Too many tokens on line")
+                byte_num = byte_num.wrapping_add(1);
+            }
+            _ => {
+                machine_code.insert_debug_info(line_num, String::new());
+                machine_code.binary_data.insert(line_num, vec![]);
+            }
+        }
+
+        if layout.is_empty() {
+            let start = if at_end { 0x10000 } else { line_start as u32 };
+            let end = start + byte_num.wrapping_sub(line_start) as u32;
+            if matches!(line.kind, StatementKind::Origin(_)) {
+                at_end = false;
+            } else if end > 0x10000 && end > start {
+                errors.push(AssemblyError::PastEndOfMemory { pc: start, span: line.span });
+                at_end = false;
+            } else {
+                at_end = end == 0x10000;
+            }
+        }
+
+        let moves = matches!(line.kind, StatementKind::Segment(_));
+        if let Some(area) = layout.get(segment).filter(|_| !moves) {
+            // Lines that run past `$FFFF` wrap around to zero
+            let end = if byte_num < line_start { byte_num as u32 + 0x10000 } else { byte_num as u32 };
+            if end > line_start as u32 && !placed {
+                errors.push(AssemblyError::NoSegment { span: line.span });
+            } else if end > line_start as u32 {
+                let end_of_area = area.start as u32 + area.size;
+                if end > end_of_area {
+                    overflows[segment].get_or_insert(line.span);
+                }
+                let segment_end = &mut machine_code.segment_ends[segment];
+                *segment_end = (*segment_end).max(end);
+
+                let initialized = match &line.kind {
+                    StatementKind::Reserve(space) | StatementKind::Align(space) => space.fill.is_some(),
+                    _ => true,
+                };
+                if !area.output && initialized {
+                    errors.push(AssemblyError::NotOutput { segment: area.name.clone(), span: line.span });
+                }
             }
-            byte_num += 1;
         }
     }
 
-    Ok((machine_code, label_locations))
+    for ((area, overflow), end) in layout.iter().zip(overflows).zip(&machine_code.segment_ends) {
+        if let Some(span) = overflow {
+            let by = end - (area.start as u32 + area.size);
+            errors.push(AssemblyError::SegmentOverflow { name: area.name.clone(), start: area.start, size: area.size, by, span });
+        }
+    }
+
+    (machine_code, label_locations, sizes)
+}
+
+/// Gives every label on a line the address the line starts at, returning the
+/// name of the label for the debug listing
+fn place_labels(
+    labels: &HashMap<String, usize>,
+    defines: &HashMap<String, Define>,
+    line_num: usize,
+    address: u16,
+    label_locations: &mut HashMap<String, Label>,
+) -> String {
+    let mut sym = "".to_string();
+    for (label, label_line) in labels {
+        if line_num == *label_line {
+            sym = label.clone();
+            label_locations.insert(label.clone(), Label { address });
+        }
+    }
+    // Defines are given the address of their line too, for `*` in their value
+    for (name, define) in defines {
+        if define.line == line_num {
+            label_locations.insert(name.clone(), Label { address });
+        }
+    }
+    sym
 }
 
 /// Takes all the bytes and formats them properly for a binary file or human readability, also
 /// transforms labels to correct values for jumps and branches.
-fn machine_code_to_str(code: &MachineCode, labels: &HashMap<String, Label>, debug: bool) -> String {
+fn machine_code_to_str(
+    code: &MachineCode,
+    labels: &HashMap<String, Label>,
+    options: &Options,
+    errors: &mut Vec<AssemblyError>,
+) -> String {
+    let debug = options.debug;
     let mut s = "".to_string();
-    let mut byte_pc = 0u16;
 
     if debug {
         for (name, define) in &code.defines {
-            s.push_str(&format!("     {:<06} =   ${:<02X}{:<02X}\n",
-                                name, define.value.upper, define.value.lower))
+            match Symbols::new(&code.defines, Some(labels), 0).symbol(name) {
+                Ok(value) => s.push_str(&format!("     {:<06} =   ${:04X}\n", name, value as u16)),
+                Err(e) => errors.push(AssemblyError::from_expr(e, define.span)),
+            }
         }
     }
 
-    for (index, line) in code.binary_data.iter().enumerate() {
+    // Each segment is output in the order of the layout, without a layout
+    // every line is output in order
+    let all_lines = 0..code.binary_data.len();
+    let groups: Vec<(Option<usize>, Vec<usize>)> = if options.layout.is_empty() {
+        vec![(None, all_lines.collect())]
+    } else {
+        (0..options.layout.len())
+            .map(|segment| (Some(segment), all_lines.clone().filter(|&line| code.segments[line] == segment).collect()))
+            .collect()
+    };
 
-        if line[0].as_str() == "*" {
-            s.push_str("* = ")
-        } else {
-            let default = "".to_string();
-            if debug {
-                s.push_str(code.debug_info.get(index).unwrap_or(&default));
-            }
-
-            let x = line.first().unwrap_or(&default);
-            let jmp_flag = x == "4C" || x == "6C" || x == "20";
-
-            for byte in line {
-                //TODO: Labels on lines above are not displayed
-                let label = byte.trim_end_matches("label");
-                if labels.contains_key(label) {
-                    let pc: u16 = byte_pc;
-                    let dest = labels.get(label).unwrap().address;
-                    if !jmp_flag {
-                        let dist = match dest.cmp(&pc) {
-                            Ordering::Less => { (dest as i8).wrapping_sub(pc as i8 - 1) - 2i8 },
-                            Ordering::Equal => { 0i8 },
-                            Ordering::Greater => { (dest as i8).wrapping_sub(pc as i8) - 1i8 },
-                        };
-                        s.push_str(&format!("{:02X} ", dist));
-                    } else {
-                        let addr = labels.get(label).unwrap().address;
-                        s.push_str(&format!("{:02X} {:02X}", addr % 0xff, addr / 0xff));
-                        byte_pc += 1;
+    for (segment, lines) in groups {
+        let area = segment.map(|segment| &options.layout[segment]);
+        let output = area.is_none_or(|area| area.output);
+        if let Some(area) = area {
+            // Segments that are not output are still listed in debug mode
+            if (!output && !debug) || (lines.is_empty() && area.fill.is_none()) {
+                continue;
+            }
+            s.push_str(&origin_marker(area.start, options));
+        }
+
+        for index in lines {
+            let line = &code.binary_data[index];
+
+            if let Some(Byte::Origin(address)) = line.first() {
+                s.push_str(&origin_marker(*address, options));
+            } else {
+                let default = "".to_string();
+                if debug {
+                    s.push_str(code.debug_info.get(index).unwrap_or(&default));
+                }
+
+                let bytes: &[Byte] = if output { line } else { &[] };
+                for byte in bytes {
+                    match byte {
+                        Byte::Value(value) => s.push_str(&format!("{:02X} ", value)),
+                        Byte::Operand { expr, mode, pc, span } => {
+                            let value = match expr.evaluate(&Symbols::new(&code.defines, Some(labels), *pc)) {
+                                Ok(value) => value,
+                                Err(e) => {
+                                    errors.push(AssemblyError::from_expr(e, *span));
+                                    continue;
+                                }
+                            };
+
+                            if *mode == Addressing::Relative {
+                                // Offsets are relative to the instruction after the branch
+                                let distance = value - (*pc as i64 + 2);
+                                if !(-128..=127).contains(&distance) {
+                                    errors.push(AssemblyError::BranchOutOfRange { distance, span: *span });
+                                    continue;
+                                }
+                                s.push_str(&format!("{:02X} ", distance as u8));
+                            } else if mode.operand_bytes() == 2 {
+                                match check_range(value, AddressSize::U16, *span) {
+                                    Ok(value) => s.push_str(&format!("{:02X} {:02X} ", value & 0xff, (value >> 8) & 0xff)),
+                                    Err(e) => errors.push(e),
+                                }
+                            } else {
+                                match check_range(value, AddressSize::U8, *span) {
+                                    Ok(value) => s.push_str(&format!("{:02X} ", value & 0xff)),
+                                    Err(e) => errors.push(e),
+                                }
+                            }
+                        }
+                        Byte::Data { expr, width, pc, span } => {
+                            let size = if *width == DataWidth::Byte { AddressSize::U8 } else { AddressSize::U16 };
+                            let value = expr.evaluate(&Symbols::new(&code.defines, Some(labels), *pc))
+                                .map_err(|e| AssemblyError::from_expr(e, *span))
+                                .and_then(|value| check_range(value, size, *span));
+                            let (low, high) = match value {
+                                Ok(value) => (value & 0xff, (value >> 8) & 0xff),
+                                Err(e) => {
+                                    errors.push(e);
+                                    continue;
+                                }
+                            };
+                            match width {
+                                DataWidth::Byte => s.push_str(&format!("{:02X} ", low)),
+                                DataWidth::Word => s.push_str(&format!("{:02X} {:02X} ", low, high)),
+                                DataWidth::BigEndianWord => s.push_str(&format!("{:02X} {:02X} ", high, low)),
+                            }
+                        }
+                        Byte::Fill { expr, count, pc, span } => {
+                            let value = expr.evaluate(&Symbols::new(&code.defines, Some(labels), *pc))
+                                .map_err(|e| AssemblyError::from_expr(e, *span))
+                                .and_then(|value| check_range(value, AddressSize::U8, *span));
+                            match value {
+                                Ok(value) => s.push_str(&format!("{:02X} ", value & 0xff).repeat(*count as usize)),
+                                Err(e) => errors.push(e),
+                            }
+                        }
+                        Byte::Origin(_) => {}
                     }
-                } else {
-                    s.push_str(&byte);
-                    s.push_str(" ");
                 }
-                byte_pc += 1;
-            }
 
-            if !s.ends_with(" ") {
-                s.push_str(" ");
+                if debug {
+                    s.push('\n');
+                }
             }
+        }
 
-            if debug {
-                s.push_str("\n");
+        if let (Some(segment), Some(area)) = (segment, area) {
+            if output && !debug {
+                s.push_str(&padding(area, code.segment_ends[segment]));
             }
         }
     }
     s
 }
 
-/// Takes any form of address and returns the binary equivalent, with exception of labels
-/// which are marked and later transformed in the last pass.
-fn address_to_string(
-    num: &str,
-    labels: &HashMap<String, usize>,
-    defines: &HashMap<String, Define>,
-) -> String {
-    if defines.contains_key(num.trim_start_matches('<').trim_start_matches('>')) {
-        let define = defines.get(
-            num.trim_start_matches('<').trim_start_matches('>')).unwrap();
-        if num.starts_with('<') {
-            format!("{:02X}", define.value.lower)
-        } else if num.starts_with('>') {
-            format!("{:02X}", define.value.upper)
-        } else {
-            match define.size {
-                AddressSize::U8 => format!("{:02X}", define.value.lower),
-                AddressSize::U16 => format!("{:02X} {:02X}", define.value.lower, define.value.upper),
-                _ => panic!(),
-            }
+/// Pads a segment that ends at `end` out to the end of its area, if it has a
+/// fill value
+fn padding(area: &Segment, end: u32) -> String {
+    let end_of_area = area.start as u32 + area.size;
+    match area.fill {
+        Some(fill) if end < end_of_area => format!("{:02X} ", fill).repeat((end_of_area - end) as usize),
+        _ => String::new(),
+    }
+}
+
+/// Turns the machine code into an object file. Segments start at zero until
+/// the linker places them, so every value that depends on where a segment or
+/// an import ends up is left as a relocation for the linker to fill in.
+fn machine_code_to_object(
+    code: &MachineCode,
+    tokens: &[Statement],
+    lines: &HashMap<String, usize>,
+    labels: &HashMap<String, Label>,
+    layout: &[Segment],
+    errors: &mut Vec<AssemblyError>,
+) -> Object {
+    let mut object = Object {
+        segments: layout.iter()
+            .map(|area| ObjectSegment { name: area.name.clone(), bytes: vec![], reserved: vec![], span: Span::default() })
+            .collect(),
+        ..Object::default()
+    };
+    let mut exports = vec![];
+    for token in tokens {
+        match &token.kind {
+            StatementKind::Import(names) => object.imports.extend(names.iter().map(|name| (name.clone(), token.span))),
+            StatementKind::Export(names) => exports.extend(names.iter().map(|name| (name, token.span))),
+            _ => {}
         }
-    } else if labels.contains_key(num) {
-        let mut num = num.to_string();
-        num.push_str("label");
-        num
-    } else {
-        let width = address_size(&num);
-        match width {
-            AddressSize::U8 => format!("{:02X}", u8_decode(&num).unwrap().lower),
-            AddressSize::U16 => {
-                let value = u16_decode(&num).unwrap();
-                format!("{:02X} {:02X}", value.lower, value.upper)
-            }
-            _ => {
-                panic!(format!("Unknown value: {}\n\
-                                Perhaps you meant to define a value or are using the wrong prefix.",
-                                num))
+    }
+
+    let relocatable = Relocatable {
+        defines: &code.defines,
+        labels: labels.iter()
+            .filter_map(|(name, label)| {
+                let line = lines.get(name).copied().or_else(|| Some(code.defines.get(name)?.line))?;
+                Some((name.clone(), (code.segments[line], label.address)))
+            })
+            .collect(),
+        imports: object.imports.iter().map(|(name, _)| name.clone()).collect(),
+    };
+    // Only used for values that have no symbols left in them
+    let constants = Symbols::new(&code.defines, None, 0);
+
+    for (line, bytes) in code.binary_data.iter().enumerate() {
+        let segment = code.segments[line];
+        let reserved = match &tokens[line].kind {
+            StatementKind::Reserve(space) | StatementKind::Align(space) => space.fill.is_none(),
+            _ => false,
+        };
+        for byte in bytes {
+            let (expr, field, pc, count, span) = match byte {
+                Byte::Value(value) => {
+                    object.segments[segment].bytes.push(*value);
+                    continue;
+                }
+                Byte::Fill { count, .. } if reserved => {
+                    object.segments[segment].reserve(*count as usize);
+                    continue;
+                }
+                Byte::Operand { expr, mode, pc, span } => (expr, Field::of_operand(*mode), *pc, 1, *span),
+                Byte::Data { expr, width, pc, span } => (expr, Field::of_data(*width), *pc, 1, *span),
+                Byte::Fill { expr, count, pc, span } => (expr, Field::Byte, *pc, *count, *span),
+                Byte::Origin(_) => continue,
+            };
+            let expr = match relocatable.rewrite(expr, segment, pc) {
+                Ok(expr) => expr,
+                Err(e) => {
+                    errors.push(AssemblyError::from_expr(e, span));
+                    continue;
+                }
+            };
+
+            let bytes = &mut object.segments[segment].bytes;
+            for _ in 0..count {
+                let offset = bytes.len();
+                // Branches depend on where they are even when their target does not
+                if expr.is_constant() && field != Field::Branch {
+                    let encoded = expr.evaluate(&constants)
+                        .map_err(|e| AssemblyError::from_expr(e, span))
+                        .and_then(|value| field.encode(value, offset as i64, span));
+                    match encoded {
+                        Ok(encoded) => bytes.extend(encoded),
+                        Err(e) => {
+                            errors.push(e);
+                            break;
+                        }
+                    }
+                } else {
+                    bytes.extend(vec![0; field.bytes()]);
+                    object.relocations.push(Relocation { segment, offset, field, expr: expr.clone(), span: Span::default() });
+                }
             }
         }
     }
-}
 
-/// Decodes asm formatted 1 byte numbers and returns in the lower byte of a HexPair
-fn u8_decode(num: &str) -> Option<HexPair> {
-    if num.starts_with('$') && num.len() == 3 {
-        Some(HexPair {
-            upper: 0,
-            lower: u8::from_str_radix(num.trim_start_matches('$'), 16).unwrap(),
-        })
-    } else if num.starts_with('%') && num.len() == 5 {
-        Some(HexPair {
-            upper: 0,
-            lower: u8::from_str_radix(num.trim_start_matches('%'), 2).unwrap(),
-        })
-    } else if num.starts_with('0') && num.len() == 4 {
-        Some(HexPair {
-            upper: 0,
-            lower: u8::from_str_radix(num.trim_start_matches('0'), 8).unwrap(),
-        })
-    } else {
-        let num = num.parse::<u8>();
-        match num {
-            Ok(n) => Some(HexPair { upper: 0, lower: n }),
-            Err(_) => None,
+    for assertion in &code.assertions {
+        match relocatable.rewrite(&assertion.condition, assertion.segment, assertion.pc) {
+            Ok(condition) if condition.is_constant() => match condition.evaluate(&constants) {
+                Ok(0) => errors.push(AssemblyError::AssertionFailed {
+                    message: assertion.message.clone(),
+                    span: assertion.span,
+                }),
+                Ok(_) => {}
+                Err(e) => errors.push(AssemblyError::from_expr(e, assertion.span)),
+            },
+            Ok(condition) => object.assertions.push(Check {
+                condition,
+                message: assertion.message.clone(),
+                span: Span::default(),
+            }),
+            Err(e) => errors.push(AssemblyError::from_expr(e, assertion.span)),
         }
     }
+
+    for (name, span) in exports {
+        match relocatable.rewrite(&Expr::Symbol(name.clone()), 0, 0) {
+            Ok(value) => object.exports.push(Export { name: name.clone(), value, span: Span::default() }),
+            Err(e) => errors.push(AssemblyError::from_expr(e, span)),
+        }
+    }
+
+    object
 }
 
-/// Decodes asm formatted 2 byte numbers and returns as HexPair
-fn u16_decode(num: &str) -> Option<HexPair> {
-    if num.starts_with('$') && num.len() == 5 {
-        Some(HexPair {
-            upper: u8::from_str_radix(&num[1..=2], 16).unwrap(),
-            lower: u8::from_str_radix(&num[3..=4], 16).unwrap(),
-        })
-    } else if num.starts_with('%') && num.len() == 9 {
-        Some(HexPair {
-            upper: u8::from_str_radix(&num[1..=4], 2).unwrap(),
-            lower: u8::from_str_radix(&num[5..=8], 2).unwrap(),
-        })
-    } else if num.starts_with('0') && num.len() == 7 {
-        Some(HexPair {
-            upper: u8::from_str_radix(&num[1..=3], 2).unwrap(),
-            lower: u8::from_str_radix(&num[4..=6], 2).unwrap(),
-        })
+/// Marks where the bytes that follow are placed, which binary output leaves out
+fn origin_marker(address: u16, options: &Options) -> String {
+    if options.binary {
+        String::new()
+    } else if options.debug {
+        format!("* = {:02X} {:02X} \n", address & 0xff, address >> 8)
     } else {
-        let num = num.parse::<i32>().unwrap_or(0);
-        let upper = num / 16;
-        let lower = num % 16;
-        Some(HexPair {
-            upper: upper as u8,
-            lower: lower as u8,
-        })
+        format!("* = {:02X} {:02X} ", address & 0xff, address >> 8)
     }
 }
 
-/// Returns if the number is 8 bit or 16 bit
-fn address_size(num: &str) -> AddressSize {
-    if num.starts_with('$') {
-        if num.len() == 3 {
-            AddressSize::U8
-        } else if num.len() == 5 {
-            AddressSize::U16
-        } else {
-            AddressSize::Unknown
+/// Reports every `.ASSERT` whose condition is false with the final label addresses
+fn check_assertions(code: &MachineCode, labels: &HashMap<String, Label>, errors: &mut Vec<AssemblyError>) {
+    for assertion in &code.assertions {
+        match assertion.condition.evaluate(&Symbols::new(&code.defines, Some(labels), assertion.pc)) {
+            Ok(0) => errors.push(AssemblyError::AssertionFailed {
+                message: assertion.message.clone(),
+                span: assertion.span,
+            }),
+            Ok(_) => {}
+            Err(e) => errors.push(AssemblyError::from_expr(e, assertion.span)),
         }
-    } else if num.starts_with('%') {
-        if num.len() == 5 {
-            AddressSize::U8
-        } else if num.len() == 9 {
-            AddressSize::U16
-        } else {
-            AddressSize::Unknown
-        }
-    } else if num.starts_with('0') {
-        if num.len() == 4 {
-            AddressSize::U8
-        } else if num.len() == 7 {
-            AddressSize::U16
-        } else {
-            AddressSize::Unknown
-        }
-    } else if num.chars().all(char::is_numeric) {
-        let parsed_num: i32 = num.parse().unwrap();
-        if parsed_num <= 0xff {
-            AddressSize::U8
-        } else if parsed_num > 0xff {
-            AddressSize::U16
-        } else {
-            AddressSize::Unknown
-        }
-    } else {
-        AddressSize::Unknown
     }
 }
 
-// TODO: Use Addressing enum instead of mode
-/// Formats opcodes in hex
-fn format_opcode(op: usize, mode: usize) -> String {
-    format!("{:02X}", &OPS_HEX[op][mode])
+/// Looks up the opcode for an addressing mode
+fn format_opcode(op: usize, mode: Addressing, span: Span) -> Result<Byte, AssemblyError> {
+    if !mode.supported_by(op) {
+        return Err(AssemblyError::InvalidAddressingMode { mnemonic: OPS[op].to_string(), mode, span });
+    }
+    Ok(Byte::Value(OPS_HEX[op][mode as usize] as u8))
+}
+
+/// Checks that a value fits in the number of bytes it is stored in, negative
+/// values are allowed as long as they fit when stored as two's complement.
+fn check_range(value: i64, size: AddressSize, span: Span) -> Result<i64, AssemblyError> {
+    let (bytes, bits) = match size {
+        AddressSize::U16 => (2, 16),
+        _ => (1, 8),
+    };
+    if value < -(1 << (bits - 1)) || value >= 1 << bits {
+        return Err(AssemblyError::ValueOutOfRange { value, bytes, span });
+    }
+    Ok(value)
+}
+
+/// Checks that a number of bytes to skip is positive and stays within memory
+fn check_count(count: i64, pc: u16, span: Span) -> Result<u16, AssemblyError> {
+    if !(0..=0xffff).contains(&count) || pc as i64 + count > 0x10000 {
+        return Err(AssemblyError::BadSize { count, pc, span });
+    }
+    Ok(count as u16)
 }
 
 #[derive(Ord, PartialOrd, Eq, PartialEq)]
@@ -603,88 +1871,313 @@ enum Mode {
     Hex,
     Debug,
     Binary,
+    Object,
+}
+
+/// Runs every pass over the first source file, returning the formatted machine
+/// code, unless there were errors, along with every error and warning found in
+/// source order. Included files and macro expansions are added to `sources` as
+/// they are read.
+fn assemble(sources: &mut Sources, options: &Options) -> (Option<String>, Vec<AssemblyError>) {
+    let mut errors = vec![];
+
+    let (tokens, lines, defines) = create_symbols_and_tokenize(sources, options, &mut errors);
+
+    // An object file has the segments it uses, which all start at zero until
+    // the linker places them
+    let object_options;
+    let options = if options.object {
+        object_options = Options { layout: object_segments(&tokens), ..options.clone() };
+        &object_options
+    } else {
+        options
+    };
+
+    let (mut machine_code_labeled, labels) =
+        tokens_to_machine_code(&tokens, &lines, &defines, options, &mut errors);
+
+    machine_code_labeled.defines = defines;
+
+    let machine_code = if options.object {
+        machine_code_to_object(&machine_code_labeled, &tokens, &lines, &labels, &options.layout, &mut errors).write()
+    } else {
+        let machine_code = machine_code_to_str(&machine_code_labeled, &labels, options, &mut errors);
+        check_assertions(&machine_code_labeled, &labels, &mut errors);
+        machine_code
+    };
+
+    let names = labels.keys().chain(machine_code_labeled.defines.keys());
+    for error in &mut errors {
+        if let AssemblyError::UndefinedSymbol { name, suggestion, .. } = error {
+            *suggestion = error::closest(name, names.clone().map(String::as_str)).map(str::to_string);
+        }
+    }
+
+    errors.sort_by_key(|e| {
+        let span = e.span();
+        (span.file, span.line, span.column)
+    });
+    if errors.iter().all(AssemblyError::is_warning) {
+        (Some(machine_code), errors)
+    } else {
+        (None, errors)
+    }
+}
+
+/// The segments of an object file in the order they are first used, starting
+/// with `CODE` for the lines before the first `.SEGMENT`
+fn object_segments(tokens: &[Statement]) -> Vec<Segment> {
+    let mut names = vec![DEFAULT_SEGMENT.to_string()];
+    for token in tokens {
+        if let StatementKind::Segment(name) = &token.kind {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+    }
+    names.into_iter()
+        .map(|name| Segment { name, start: 0, size: 0x10000, fill: None, output: true })
+        .collect()
+}
+
+/// Links object files into machine code placed by the layout, returning it
+/// unless there were errors. The object files are added to `sources` so
+/// errors can show the records they are about.
+fn link_objects<'a>(
+    paths: impl Iterator<Item = &'a str>,
+    options: &Options,
+    sources: &mut Sources,
+) -> (Option<String>, Vec<AssemblyError>) {
+    let mut errors = vec![];
+    let mut objects = vec![];
+    for path in paths {
+        sources.files.push(SourceFile { name: path.to_string(), code: read_file(path) });
+        let file = sources.files.len() - 1;
+        match Object::parse(&sources.files[file].code, file) {
+            Ok(object) => objects.push(object),
+            Err(e) => errors.extend(e),
+        }
+    }
+
+    let mut s = String::new();
+    if errors.is_empty() {
+        let images = object::link(&objects, &options.layout, &mut errors);
+        for (area, image) in options.layout.iter().zip(images) {
+            if !area.output || (image.is_empty() && area.fill.is_none()) {
+                continue;
+            }
+            s.push_str(&origin_marker(area.start, options));
+            for byte in &image {
+                s.push_str(&format!("{:02X} ", byte));
+            }
+            s.push_str(&padding(area, area.start as u32 + image.len() as u32));
+        }
+    }
+
+    errors.sort_by_key(|e| {
+        let span = e.span();
+        (span.file, span.line, span.column)
+    });
+    if errors.is_empty() {
+        (Some(s), errors)
+    } else {
+        (None, errors)
+    }
+}
+
+/// Reads a file named on the command line, exiting if it can not be read
+fn read_file(path: &str) -> String {
+    match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(_) => {
+            eprintln!("Unable to read file: {}", path);
+            process::exit(1);
+        }
+    }
+}
+
+/// Reads and parses the layout file, if one is given. The layout is kept with
+/// the sources so errors in it can be shown.
+fn read_layout(path: Option<&str>, sources: &mut Sources) -> Result<Vec<Segment>, Vec<AssemblyError>> {
+    match path {
+        Some(path) => {
+            sources.files.push(SourceFile { name: path.to_string(), code: read_file(path) });
+            let file = sources.files.len() - 1;
+            layout::parse(&sources.files[file].code, file)
+        }
+        None => Ok(vec![]),
+    }
+}
+
+/// Opens the output file, exiting if it can not be created
+fn create_output(output_file: &str) -> File {
+    match File::create(output_file) {
+        Ok(file) => file,
+        Err(_) => {
+            eprintln!("Unable to create file: {}", output_file);
+            process::exit(1);
+        }
+    }
 }
 
 /// Main call for the binary, processes arguments and calls functions to do processing
 fn main() {
+    // Shared by assembling and linking
+    let binary = Arg::with_name("binary")
+        .short("b")
+        .long("binary")
+        .help("Outputs the machine code in a binary format");
+    let output = Arg::with_name("OUTPUT")
+        .short("o")
+        .long("output")
+        .takes_value(true)
+        .help("A file to output the machine code to");
+    let layout = Arg::with_name("layout")
+        .short("l")
+        .long("layout")
+        .takes_value(true)
+        .help("A file giving the area of memory each segment is placed in");
+    let max_errors = Arg::with_name("max-errors")
+        .long("max-errors")
+        .takes_value(true)
+        .default_value("20")
+        .help("The most errors to report, 0 for no limit");
 
     let matches = App::new("6502 Assembler")
         .version("0.1")
         .author("Grant G.")
         .about("Assembles 6502 Assembly into machine code")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::with_name("debug")
             .short("d")
             .long("debug")
             .conflicts_with("binary")
             .help("Outputs machine code formatted with helpful information for debugging"))
-        .arg(Arg::with_name("binary")
-            .short("b")
-            .long("binary")
-            .help("Outputs the machine code in a binary format"))
+        .arg(binary.clone())
+        .arg(Arg::with_name("object")
+            .long("object")
+            .conflicts_with_all(&["debug", "binary", "layout"])
+            .help("Outputs an object file to be linked with others by `link`"))
         .arg(Arg::with_name("INPUT")
             .required(true)
             .index(1))
-        .arg(Arg::with_name("OUTPUT")
-            .short("o")
-            .long("output")
+        .arg(output.clone())
+        .arg(Arg::with_name("case-sensitive")
+            .long("case-sensitive")
+            .help("Treats labels and defines that differ only in case as different symbols"))
+        .arg(Arg::with_name("include")
+            .short("I")
+            .long("include")
             .takes_value(true)
-            .help("A file to output the machine code to"))
+            .multiple(true)
+            .number_of_values(1)
+            .help("A directory to search for included files"))
+        .arg(layout.clone())
+        .arg(max_errors.clone())
+        .subcommand(SubCommand::with_name("link")
+            .about("Links object files made with `--object` into machine code")
+            .arg(Arg::with_name("OBJECTS")
+                .required(true)
+                .multiple(true)
+                .index(1))
+            .arg(layout.required(true))
+            .arg(binary)
+            .arg(output)
+            .arg(max_errors))
         .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("link") {
+        let mut sources = Sources { files: vec![], expansions: vec![] };
+        let mode = if matches.is_present("binary") { Mode::Binary } else { Mode::Hex };
+        let result = match read_layout(matches.value_of("layout"), &mut sources) {
+            Ok(layout) => {
+                let options = Options {
+                    debug: false,
+                    binary: mode == Mode::Binary,
+                    case_sensitive: false,
+                    include_dirs: vec![],
+                    layout,
+                    object: false,
+                };
+                link_objects(matches.values_of("OBJECTS").unwrap(), &options, &mut sources)
+            }
+            Err(errors) => (None, errors),
+        };
+        return finish(&sources, result, mode, matches);
+    }
+
     let mode = {
         if matches.is_present("debug") {
             Mode::Debug
         } else if matches.is_present("binary") {
             Mode::Binary
+        } else if matches.is_present("object") {
+            Mode::Object
         } else {
             Mode::Hex
         }
     };
 
     let file= matches.value_of("INPUT").unwrap();
-    let output = matches.value_of("OUTPUT");
+    let code = read_file(file);
 
-    let code = match fs::read_to_string(&file) {
-        Ok(s) => s,
-        Err(_) => {
-            eprintln!("Unable to read file: {}", &file);
-            return;
+    let mut sources = Sources { files: vec![SourceFile { name: file.to_string(), code }], expansions: vec![] };
+
+    let result = match read_layout(matches.value_of("layout"), &mut sources) {
+        Ok(layout) => {
+            let options = Options {
+                debug: mode == Mode::Debug,
+                binary: mode == Mode::Binary,
+                case_sensitive: matches.is_present("case-sensitive"),
+                include_dirs: matches.values_of("include").map(|dirs| dirs.map(PathBuf::from).collect()).unwrap_or_default(),
+                layout,
+                object: mode == Mode::Object,
+            };
+            assemble(&mut sources, &options)
         }
+        Err(errors) => (None, errors),
     };
+    finish(&sources, result, mode, &matches);
+}
 
-    // Transforms the code to uppercase since 6502 asm is case insensitive
-    let code = code.to_uppercase();
-
-    let (tokens, labels, defines) = create_symbols_and_tokenize(&code);
-
-    let (mut machine_code_labeled, labels) =
-        tokens_to_machine_code(&tokens, &labels, &defines).unwrap();
-
-    machine_code_labeled.defines = defines;
+/// Reports the errors and writes out the machine code, or exits if there was
+/// none because of the errors
+fn finish(sources: &Sources, result: (Option<String>, Vec<AssemblyError>), mode: Mode, matches: &ArgMatches) {
+    let (machine_code, errors) = result;
+    let output = matches.value_of("OUTPUT");
+    let max_errors = value_t!(matches, "max-errors", usize).unwrap_or_else(|e| e.exit());
 
-    let machine_code = machine_code_to_str(&machine_code_labeled, &labels, matches.is_present("debug"));
+    let color = io::stderr().is_terminal();
+    let shown = if max_errors == 0 { errors.len() } else { errors.len().min(max_errors) };
+    for error in &errors[..shown] {
+        eprintln!("{}", error.render(sources, color));
+    }
+    let machine_code = match machine_code {
+        Some(machine_code) => {
+            if shown < errors.len() {
+                eprintln!("warning: {} more warnings not shown", errors.len() - shown);
+            }
+            machine_code
+        }
+        None => {
+            if shown < errors.len() {
+                eprintln!("error: aborting after {} errors, {} more not shown", shown, errors.len() - shown);
+            }
+            process::exit(1);
+        }
+    };
 
     if mode == Mode::Binary {
+        let mut bytes = "6502ROM...".as_bytes().to_vec();
+        bytes.extend(machine_code.split_whitespace().map(|byte| u8::from_str_radix(byte, 16).unwrap()));
         match output {
-            Some(output_file) => {
-                let mut file = File::create(output_file).unwrap();
-                let header = "6502ROM...".as_bytes();
-                file.write(header).expect("Unable to write to file");
-                for byte in machine_code.split_whitespace() {
-                    file.write(&[u8::from_str_radix(byte, 16).unwrap()]).expect("Unable to write to file");
-                }
-            }
-            None => {
-                print!("{}", "6502ROM...");
-                for byte in machine_code.split_whitespace() {
-                    print!("{}", u8::from_str_radix(byte, 16).unwrap() as char);
-                }
-            }
+            Some(output_file) => create_output(output_file).write_all(&bytes).expect("Unable to write to file"),
+            None => io::stdout().write_all(&bytes).expect("Unable to write to output"),
         }
     } else {
         match output {
             Some(output_file) => {
-                let mut file = File::create(output_file).unwrap();
+                let mut file = create_output(output_file);
                 file.write_all(machine_code.as_ref()).expect("Unable to write to file");
             }
             None => {
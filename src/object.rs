@@ -0,0 +1,673 @@
+use std::collections::HashMap;
+
+use crate::error::{self, AssemblyError};
+use crate::expr::{BinaryOp, Context, Expr, ExprError, UnaryOp};
+use crate::layout::Segment;
+use crate::lexer::Span;
+use crate::parser::DataWidth;
+use crate::{check_range, AddressSize, Addressing};
+
+/// The first line of every object file, the number changes whenever the
+/// format does
+const HEADER: &str = "6502OBJ 1";
+
+/// Starts the symbols standing for the address of a segment of the object
+const SEGMENT_PREFIX: &str = "seg:";
+
+/// Starts the symbols imported from other objects
+const IMPORT_PREFIX: &str = "sym:";
+
+/// Names of the operators in relocation expressions
+const BINARY_OPS: [(&str, BinaryOp); 18] = [
+    ("LOR", BinaryOp::LogicalOr),
+    ("LAND", BinaryOp::LogicalAnd),
+    ("OR", BinaryOp::Or),
+    ("XOR", BinaryOp::Xor),
+    ("AND", BinaryOp::And),
+    ("EQ", BinaryOp::Equal),
+    ("NE", BinaryOp::NotEqual),
+    ("LT", BinaryOp::Less),
+    ("LE", BinaryOp::LessEqual),
+    ("GT", BinaryOp::Greater),
+    ("GE", BinaryOp::GreaterEqual),
+    ("SHL", BinaryOp::ShiftLeft),
+    ("SHR", BinaryOp::ShiftRight),
+    ("ADD", BinaryOp::Add),
+    ("SUB", BinaryOp::Subtract),
+    ("MUL", BinaryOp::Multiply),
+    ("DIV", BinaryOp::Divide),
+    ("MOD", BinaryOp::Modulo),
+];
+
+const UNARY_OPS: [(&str, UnaryOp); 5] = [
+    ("NEG", UnaryOp::Negate),
+    ("CPL", UnaryOp::Complement),
+    ("NOT", UnaryOp::Not),
+    ("LO", UnaryOp::Low),
+    ("HI", UnaryOp::High),
+];
+
+/// The symbol standing for the address of a segment in relocation expressions
+pub fn segment_symbol(index: usize) -> String {
+    format!("{}{}", SEGMENT_PREFIX, index)
+}
+
+/// How a relocated value is stored
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Field {
+    Byte,
+    /// Two bytes, low byte first
+    Word,
+    /// Two bytes, high byte first
+    BigEndianWord,
+    /// The distance from the end of a branch to its target
+    Branch,
+}
+
+impl Field {
+    const ALL: [Field; 4] = [Field::Byte, Field::Word, Field::BigEndianWord, Field::Branch];
+
+    /// How the operand of an instruction using the addressing mode is stored
+    pub fn of_operand(mode: Addressing) -> Field {
+        match mode {
+            Addressing::Relative => Field::Branch,
+            _ if mode.operand_bytes() == 2 => Field::Word,
+            _ => Field::Byte,
+        }
+    }
+
+    pub fn of_data(width: DataWidth) -> Field {
+        match width {
+            DataWidth::Byte => Field::Byte,
+            DataWidth::Word => Field::Word,
+            DataWidth::BigEndianWord => Field::BigEndianWord,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Field::Byte => "BYTE",
+            Field::Word => "WORD",
+            Field::BigEndianWord => "DBYT",
+            Field::Branch => "BRANCH",
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        match self {
+            Field::Word | Field::BigEndianWord => 2,
+            Field::Byte | Field::Branch => 1,
+        }
+    }
+
+    /// Encodes a value stored at `address`, checking that it fits
+    pub fn encode(self, value: i64, address: i64, span: Span) -> Result<Vec<u8>, AssemblyError> {
+        Ok(match self {
+            Field::Byte => vec![check_range(value, AddressSize::U8, span)? as u8],
+            Field::Word => {
+                let value = check_range(value, AddressSize::U16, span)?;
+                vec![value as u8, (value >> 8) as u8]
+            }
+            Field::BigEndianWord => {
+                let value = check_range(value, AddressSize::U16, span)?;
+                vec![(value >> 8) as u8, value as u8]
+            }
+            Field::Branch => {
+                // The operand is the last byte of the branch
+                let distance = value - (address + 1);
+                if !(-128..=127).contains(&distance) {
+                    return Err(AssemblyError::BranchOutOfRange { distance, span });
+                }
+                vec![distance as u8]
+            }
+        })
+    }
+}
+
+/// The bytes of one segment of an object, before it is given an address
+pub struct ObjectSegment {
+    pub name: String,
+    pub bytes: Vec<u8>,
+    /// The `(offset, length)` of each run of bytes that is only reserved, the
+    /// only bytes that can go in an area that is not output
+    pub reserved: Vec<(usize, usize)>,
+    /// Where the segment is described in the object file
+    pub span: Span,
+}
+
+impl ObjectSegment {
+    /// Adds `len` reserved bytes to the end of the segment
+    pub fn reserve(&mut self, len: usize) {
+        let offset = self.bytes.len();
+        self.bytes.resize(offset + len, 0);
+        match self.reserved.last_mut() {
+            Some((start, run)) if *start + *run == offset => *run += len,
+            _ => self.reserved.push((offset, len)),
+        }
+    }
+}
+
+/// A value written into a segment once the addresses it depends on are known
+pub struct Relocation {
+    pub segment: usize,
+    /// Where the value goes, counting from the start of the segment
+    pub offset: usize,
+    pub field: Field,
+    /// The value in terms of the addresses of segments and imports
+    pub expr: Expr,
+    pub span: Span,
+}
+
+/// A symbol other objects can import
+pub struct Export {
+    pub name: String,
+    pub value: Expr,
+    pub span: Span,
+}
+
+/// A `.ASSERT` that can only be checked once the object is linked
+pub struct Check {
+    pub condition: Expr,
+    pub message: Option<String>,
+    pub span: Span,
+}
+
+/// An assembled file whose segments have not been given addresses yet. The
+/// spans point at the records of the object file it was read from, so link
+/// errors can show them.
+#[derive(Default)]
+pub struct Object {
+    pub segments: Vec<ObjectSegment>,
+    pub relocations: Vec<Relocation>,
+    pub imports: Vec<(String, Span)>,
+    pub exports: Vec<Export>,
+    pub assertions: Vec<Check>,
+}
+
+impl Object {
+    /// Formats the object file with one record per line, as described in the
+    /// README
+    pub fn write(&self) -> String {
+        let mut s = format!("{}\n", HEADER);
+        for (index, segment) in self.segments.iter().enumerate() {
+            s.push_str(&format!("SEGMENT {} {:04X}\n", segment.name, segment.bytes.len()));
+            // Segments start out as zeros, so rows of zeros are left out
+            for (row, chunk) in segment.bytes.chunks(16).enumerate().filter(|(_, chunk)| chunk.iter().any(|&b| b != 0)) {
+                let bytes: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+                s.push_str(&format!("BYTES {} {:04X} {}\n", index, row * 16, bytes.join(" ")));
+            }
+            for (offset, len) in &segment.reserved {
+                s.push_str(&format!("RESERVE {} {:04X} {:04X}\n", index, offset, len));
+            }
+        }
+        for relocation in &self.relocations {
+            s.push_str(&format!(
+                "RELOC {} {:04X} {} {}\n",
+                relocation.segment, relocation.offset, relocation.field.name(), write_expr(&relocation.expr)
+            ));
+        }
+        for (name, _) in &self.imports {
+            s.push_str(&format!("IMPORT {}\n", name));
+        }
+        for export in &self.exports {
+            s.push_str(&format!("EXPORT {} {}\n", export.name, write_expr(&export.value)));
+        }
+        for assertion in &self.assertions {
+            s.push_str(&format!("ASSERT {}", write_expr(&assertion.condition)));
+            if let Some(message) = &assertion.message {
+                s.push_str(&format!(" ; {}", message.replace(['\n', '\r'], " ")));
+            }
+            s.push('\n');
+        }
+        s
+    }
+
+    /// Reads an object file made by `write`
+    pub fn parse(text: &str, file: usize) -> Result<Object, Vec<AssemblyError>> {
+        let mut object = Object::default();
+        let mut errors = vec![];
+        let span = |index: usize, line: &str| Span { file, line: index + 1, column: 1, len: line.chars().count(), expansion: 0 };
+
+        let mut lines = text.lines().enumerate();
+        match lines.next() {
+            Some((_, line)) if line.trim_end() == HEADER => {}
+            first => {
+                let line = first.map_or("", |(_, line)| line);
+                return Err(vec![AssemblyError::Syntax {
+                    message: format!("not an object file, which starts with `{}`", HEADER),
+                    span: span(0, line),
+                }]);
+            }
+        }
+
+        for (index, line) in lines {
+            if let Err(message) = object.record(line, span(index, line)) {
+                errors.push(AssemblyError::Syntax { message, span: span(index, line) });
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(object)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Reads one line of an object file
+    fn record(&mut self, line: &str, span: Span) -> Result<(), String> {
+        // Only the message of an assertion can have spaces in it
+        let (line, message) = match line.split_once(" ; ") {
+            Some((line, message)) => (line, Some(message.to_string())),
+            None => (line, None),
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        match words.as_slice() {
+            [] => {}
+            ["SEGMENT", name, size] => {
+                let size = hex(size)?;
+                if size > 0x10000 {
+                    return Err(format!("segment `{}` is larger than memory", name));
+                }
+                self.segments.push(ObjectSegment { name: name.to_string(), bytes: vec![0; size], reserved: vec![], span });
+            }
+            ["RESERVE", segment, offset, len] => {
+                let len = hex(len)?;
+                let (segment, offset) = self.place(segment, offset, len)?;
+                self.segments[segment].reserved.push((offset, len));
+            }
+            ["BYTES", segment, offset, bytes @ ..] => {
+                let (segment, offset) = self.place(segment, offset, bytes.len())?;
+                for (i, byte) in bytes.iter().enumerate() {
+                    self.segments[segment].bytes[offset + i] = u8::from_str_radix(byte, 16)
+                        .map_err(|_| format!("invalid byte `{}`", byte))?;
+                }
+            }
+            ["RELOC", segment, offset, field, expr @ ..] => {
+                let field = Field::ALL.iter().copied().find(|f| f.name() == *field)
+                    .ok_or_else(|| format!("unknown field `{}`", field))?;
+                let (segment, offset) = self.place(segment, offset, field.bytes())?;
+                let expr = self.read_expr(expr)?;
+                self.relocations.push(Relocation { segment, offset, field, expr, span });
+            }
+            ["IMPORT", name] => self.imports.push((name.to_string(), span)),
+            ["EXPORT", name, value @ ..] => {
+                let value = self.read_expr(value)?;
+                self.exports.push(Export { name: name.to_string(), value, span });
+            }
+            ["ASSERT", condition @ ..] => {
+                let condition = self.read_expr(condition)?;
+                self.assertions.push(Check { condition, message, span });
+            }
+            [record, ..] => return Err(format!("unknown record `{}`", record)),
+        }
+        Ok(())
+    }
+
+    /// Checks that `len` bytes at an offset fit in a segment, returning the
+    /// index of the segment and the offset
+    fn place(&self, segment: &str, offset: &str, len: usize) -> Result<(usize, usize), String> {
+        let index = segment.parse::<usize>().ok()
+            .filter(|&index| index < self.segments.len())
+            .ok_or_else(|| format!("there is no segment `{}`", segment))?;
+        let offset = hex(offset)?;
+        if offset.checked_add(len).is_none_or(|end| end > self.segments[index].bytes.len()) {
+            return Err(format!("offset ${:04X} is past the end of segment {}", offset, index));
+        }
+        Ok((index, offset))
+    }
+
+    /// Reads an expression written in postfix order
+    fn read_expr(&self, words: &[&str]) -> Result<Expr, String> {
+        let mut stack = vec![];
+        for word in words {
+            let missing = || format!("`{}` is missing an operand", word);
+            let expr = if let Some(&(_, op)) = BINARY_OPS.iter().find(|(name, _)| name == word) {
+                let r = stack.pop().ok_or_else(missing)?;
+                let l = stack.pop().ok_or_else(missing)?;
+                Expr::Binary(op, Box::new(l), Box::new(r))
+            } else if let Some(&(_, op)) = UNARY_OPS.iter().find(|(name, _)| name == word) {
+                Expr::Unary(op, Box::new(stack.pop().ok_or_else(missing)?))
+            } else if let Some(index) = word.strip_prefix(SEGMENT_PREFIX) {
+                match index.parse::<usize>() {
+                    Ok(index) if index < self.segments.len() => Expr::Symbol(word.to_string()),
+                    _ => return Err(format!("there is no segment `{}`", index)),
+                }
+            } else if let Some(name) = word.strip_prefix(IMPORT_PREFIX) {
+                Expr::Symbol(name.to_string())
+            } else {
+                let value = word.parse().map_err(|_| format!("unknown term `{}`", word))?;
+                Expr::Number(value, AddressSize::U16)
+            };
+            stack.push(expr);
+        }
+
+        match (stack.pop(), stack.is_empty()) {
+            (Some(expr), true) => Ok(expr),
+            (None, _) => Err("missing an expression".to_string()),
+            (Some(_), false) => Err("expression has values without an operator".to_string()),
+        }
+    }
+}
+
+/// Parses a hex number from an object file
+fn hex(word: &str) -> Result<usize, String> {
+    usize::from_str_radix(word, 16).map_err(|_| format!("invalid hex number `{}`", word))
+}
+
+/// Writes an expression in postfix order, each operator after its operands
+fn write_expr(expr: &Expr) -> String {
+    match expr {
+        Expr::Number(n, _) => n.to_string(),
+        Expr::Char(c) => (*c as i64).to_string(),
+        Expr::Symbol(name) if name.starts_with(SEGMENT_PREFIX) => name.clone(),
+        Expr::Symbol(name) => format!("{}{}", IMPORT_PREFIX, name),
+        Expr::Pc => unreachable!("`*` is replaced by an address in its segment before being written"),
+        Expr::Unary(op, e) => {
+            let name = UNARY_OPS.iter().find(|(_, o)| o == op).map_or("", |(name, _)| name);
+            format!("{} {}", write_expr(e), name)
+        }
+        Expr::Binary(op, l, r) => {
+            let name = BINARY_OPS.iter().find(|(_, o)| o == op).map_or("", |(name, _)| name);
+            format!("{} {} {}", write_expr(l), write_expr(r), name)
+        }
+    }
+}
+
+/// Resolves the symbols of one object once every segment has an address
+struct LinkSymbols<'a> {
+    /// The address of each segment of the object, if it was placed
+    bases: &'a [Option<u16>],
+    exports: &'a HashMap<&'a str, (i64, Span)>,
+}
+
+impl<'a> Context for LinkSymbols<'a> {
+    fn symbol(&self, name: &str) -> Result<i64, ExprError> {
+        let value = match name.strip_prefix(SEGMENT_PREFIX) {
+            Some(index) => index.parse::<usize>().ok().and_then(|index| *self.bases.get(index)?).map(|base| base as i64),
+            None => self.exports.get(name).map(|&(value, _)| value),
+        };
+        value.ok_or_else(|| ExprError::Undefined(name.to_string()))
+    }
+
+    fn symbol_size(&self, _name: &str) -> AddressSize {
+        AddressSize::Unknown
+    }
+
+    fn pc(&self) -> i64 {
+        0
+    }
+}
+
+/// Places the segments of every object one after another in the area of the
+/// layout with the same name, resolves each import to the export of the same
+/// name and fills in every relocation, returning the bytes placed in each area.
+pub fn link(objects: &[Object], layout: &[Segment], errors: &mut Vec<AssemblyError>) -> Vec<Vec<u8>> {
+    let mut images: Vec<Vec<u8>> = vec![vec![]; layout.len()];
+    // The area each segment of each object is in and where in the area it starts
+    let mut placed: Vec<Vec<Option<(usize, usize)>>> = objects.iter().map(|object| vec![None; object.segments.len()]).collect();
+
+    for (index, (area, image)) in layout.iter().zip(&mut images).enumerate() {
+        let mut overflow = None;
+        for (object, placed) in objects.iter().zip(&mut placed) {
+            for (segment, place) in object.segments.iter().zip(placed).filter(|(segment, _)| segment.name == area.name) {
+                if image.len() + segment.bytes.len() > area.size as usize {
+                    overflow.get_or_insert(segment.span);
+                }
+                let reserved: usize = segment.reserved.iter().map(|(_, len)| len).sum();
+                if !area.output && reserved < segment.bytes.len() {
+                    errors.push(AssemblyError::NotOutput { segment: area.name.clone(), span: segment.span });
+                }
+                *place = Some((index, image.len()));
+                image.extend(&segment.bytes);
+            }
+        }
+        if let Some(span) = overflow {
+            let by = (image.len() - area.size as usize) as u32;
+            errors.push(AssemblyError::SegmentOverflow { name: area.name.clone(), start: area.start, size: area.size, by, span });
+        }
+    }
+
+    for (object, placed) in objects.iter().zip(&placed) {
+        for (segment, place) in object.segments.iter().zip(placed) {
+            // Every object has a segment for the lines before its first
+            // `.SEGMENT`, which is often empty
+            if place.is_none() && !segment.bytes.is_empty() {
+                errors.push(AssemblyError::UnknownSegment {
+                    name: segment.name.clone(),
+                    segments: layout.iter().map(|area| area.name.clone()).collect(),
+                    span: segment.span,
+                });
+            }
+        }
+    }
+
+    let bases: Vec<Vec<Option<u16>>> = placed.iter()
+        .map(|placed| {
+            placed.iter()
+                .map(|place| place.map(|(area, offset)| (layout[area].start as usize + offset) as u16))
+                .collect()
+        })
+        .collect();
+
+    // Exports can only refer to their own object
+    let no_exports = HashMap::new();
+    let mut exports: HashMap<&str, (i64, Span)> = HashMap::new();
+    for (object, bases) in objects.iter().zip(&bases) {
+        let symbols = LinkSymbols { bases, exports: &no_exports };
+        for export in &object.exports {
+            match (export.value.evaluate(&symbols), exports.get(export.name.as_str())) {
+                (Ok(_), Some(&(_, previous))) => errors.push(AssemblyError::DuplicateSymbol {
+                    name: export.name.clone(),
+                    span: export.span,
+                    previous,
+                }),
+                (Ok(value), None) => {
+                    exports.insert(&export.name, (value, export.span));
+                }
+                (Err(e), _) => errors.push(AssemblyError::from_expr(e, export.span)),
+            }
+        }
+    }
+
+    for ((object, bases), placed) in objects.iter().zip(&bases).zip(&placed) {
+        for (name, span) in &object.imports {
+            if !exports.contains_key(name.as_str()) {
+                let suggestion = error::closest(name, exports.keys().copied()).map(str::to_string);
+                errors.push(AssemblyError::UndefinedSymbol { name: name.clone(), suggestion, span: *span });
+            }
+        }
+        // Imports that were never exported and segments that were not placed
+        // are reported once, above
+        let unresolved = |e: &ExprError| match e {
+            ExprError::Undefined(name) => {
+                name.starts_with(SEGMENT_PREFIX) || object.imports.iter().any(|(import, _)| import == name)
+            }
+            _ => false,
+        };
+
+        let symbols = LinkSymbols { bases, exports: &exports };
+        for relocation in &object.relocations {
+            let (area, start, base) = match (placed[relocation.segment], bases[relocation.segment]) {
+                (Some((area, start)), Some(base)) => (area, start, base),
+                _ => continue,
+            };
+            let address = base as i64 + relocation.offset as i64;
+            let bytes = match relocation.expr.evaluate(&symbols) {
+                Ok(value) => relocation.field.encode(value, address, relocation.span),
+                Err(e) if unresolved(&e) => continue,
+                Err(e) => Err(AssemblyError::from_expr(e, relocation.span)),
+            };
+            match bytes {
+                Ok(bytes) => {
+                    let start = start + relocation.offset;
+                    images[area][start..start + bytes.len()].copy_from_slice(&bytes);
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+
+        for assertion in &object.assertions {
+            match assertion.condition.evaluate(&symbols) {
+                Ok(0) => errors.push(AssemblyError::AssertionFailed {
+                    message: assertion.message.clone(),
+                    span: assertion.span,
+                }),
+                Ok(_) => {}
+                Err(e) if unresolved(&e) => {}
+                Err(e) => errors.push(AssemblyError::from_expr(e, assertion.span)),
+            }
+        }
+    }
+
+    images
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Object {
+        Object::parse(text, 0).unwrap_or_else(|errors| panic!("{}", errors[0]))
+    }
+
+    fn area(name: &str, start: u16, output: bool) -> Segment {
+        Segment { name: name.to_string(), start, size: 0x100, fill: None, output }
+    }
+
+    fn num(n: i64) -> Box<Expr> {
+        Box::new(Expr::Number(n, AddressSize::U16))
+    }
+
+    fn symbol(name: &str) -> Box<Expr> {
+        Box::new(Expr::Symbol(name.to_string()))
+    }
+
+    #[test]
+    fn write_and_parse_round_trip() {
+        let mut code = ObjectSegment { name: "CODE".to_string(), bytes: vec![0xa9, 0x01, 0x20, 0, 0], reserved: vec![], span: Span::default() };
+        code.bytes.extend(vec![0; 20]);
+        code.bytes.push(0x60);
+        let mut zeropage = ObjectSegment { name: "ZP".to_string(), bytes: vec![], reserved: vec![], span: Span::default() };
+        zeropage.reserve(2);
+        zeropage.reserve(1);
+
+        // `>(PRINT + ZP - 1)` and `!(seg:0 == 3 || ~PRINT < -2)`
+        let high = Expr::Unary(UnaryOp::High, Box::new(Expr::Binary(
+            BinaryOp::Subtract,
+            Box::new(Expr::Binary(BinaryOp::Add, symbol("PRINT"), symbol("seg:1"))),
+            num(1),
+        )));
+        let condition = Expr::Unary(UnaryOp::Not, Box::new(Expr::Binary(
+            BinaryOp::LogicalOr,
+            Box::new(Expr::Binary(BinaryOp::Equal, symbol("seg:0"), num(3))),
+            Box::new(Expr::Binary(BinaryOp::Less, Box::new(Expr::Unary(UnaryOp::Complement, symbol("PRINT"))), num(-2))),
+        )));
+        let object = Object {
+            segments: vec![code, zeropage],
+            relocations: vec![Relocation { segment: 0, offset: 3, field: Field::Word, expr: high.clone(), span: Span::default() }],
+            imports: vec![("PRINT".to_string(), Span::default())],
+            exports: vec![Export { name: "START".to_string(), value: *symbol("seg:0"), span: Span::default() }],
+            assertions: vec![Check { condition: condition.clone(), message: Some("too\nbig".to_string()), span: Span::default() }],
+        };
+
+        let text = object.write();
+        assert_eq!(text.lines().next(), Some(HEADER));
+        // The row of zeros in the middle is left out
+        assert_eq!(text.matches("BYTES").count(), 2);
+        assert!(text.contains("RESERVE 1 0000 0003\n"));
+
+        let parsed = parse(&text);
+        assert_eq!(parsed.write(), text);
+        assert_eq!(parsed.segments[0].bytes, object.segments[0].bytes);
+        assert_eq!(parsed.segments[1].reserved, vec![(0, 3)]);
+        assert_eq!(parsed.relocations[0].expr, high);
+        assert_eq!(parsed.relocations[0].field, Field::Word);
+        assert_eq!(parsed.assertions[0].condition, condition);
+        assert_eq!(parsed.assertions[0].message.as_deref(), Some("too big"));
+        assert_eq!(parsed.imports[0].0, "PRINT");
+        assert_eq!(parsed.exports[0].value, *symbol("seg:0"));
+    }
+
+    #[test]
+    fn reads_postfix_expressions() {
+        let object = parse("6502OBJ 1\nSEGMENT CODE 0000\n");
+        let read = |text: &str| object.read_expr(&text.split_whitespace().collect::<Vec<_>>());
+        assert_eq!(read("1 2 3 MUL ADD"), Ok(Expr::Binary(BinaryOp::Add, num(1), Box::new(Expr::Binary(BinaryOp::Multiply, num(2), num(3))))));
+        assert_eq!(read("sym:X NEG"), Ok(Expr::Unary(UnaryOp::Negate, symbol("X"))));
+        assert_eq!(read("seg:0"), Ok(*symbol("seg:0")));
+        assert_eq!(read("1 ADD"), Err("`ADD` is missing an operand".to_string()));
+        assert_eq!(read("1 2"), Err("expression has values without an operator".to_string()));
+        assert_eq!(read("seg:1"), Err("there is no segment `1`".to_string()));
+        assert_eq!(read("$10"), Err("unknown term `$10`".to_string()));
+        assert_eq!(read(""), Err("missing an expression".to_string()));
+    }
+
+    #[test]
+    fn rejects_bad_records() {
+        let error = |text: &str| match Object::parse(text, 0) {
+            Err(errors) => errors[0].to_string(),
+            Ok(_) => panic!("`{}` should not parse", text),
+        };
+        assert_eq!(error("6502OBJ 9\n"), "not an object file, which starts with `6502OBJ 1`");
+        assert_eq!(error("6502OBJ 1\nSEGMENT CODE 0002\nBYTES 0 0001 01 02\n"), "offset $0001 is past the end of segment 0");
+        assert_eq!(error("6502OBJ 1\nSEGMENT CODE 0002\nRESERVE 0 0000 0003\n"), "offset $0000 is past the end of segment 0");
+        assert_eq!(
+            error("6502OBJ 1\nSEGMENT CODE 0002\nRESERVE 0 FFFFFFFFFFFFFFFF 0001\n"),
+            "offset $FFFFFFFFFFFFFFFF is past the end of segment 0"
+        );
+        assert_eq!(error("6502OBJ 1\nRELOC 0 0000 BYTE 1\n"), "there is no segment `0`");
+        assert_eq!(error("6502OBJ 1\nSEGMENT CODE 0001\nRELOC 0 0000 LONG 1\n"), "unknown field `LONG`");
+        assert_eq!(error("6502OBJ 1\nLABEL X\n"), "unknown record `LABEL`");
+    }
+
+    #[test]
+    fn link_applies_relocations() {
+        // JSR PRINT, BNE to the start of the segment, LDA #>(DATA+1)
+        let main = parse(
+            "6502OBJ 1\n\
+             SEGMENT CODE 0007\n\
+             BYTES 0 0000 20 00 00 D0 00 A9 00\n\
+             SEGMENT DATA 0002\n\
+             BYTES 1 0000 AA BB\n\
+             RELOC 0 0001 WORD sym:PRINT\n\
+             RELOC 0 0004 BRANCH seg:0\n\
+             RELOC 0 0006 BYTE seg:1 1 ADD HI\n\
+             IMPORT PRINT\n",
+        );
+        let print = parse("6502OBJ 1\nSEGMENT CODE 0001\nBYTES 0 0000 60\nEXPORT PRINT seg:0\n");
+        let layout = [area("CODE", 0x8000, true), area("DATA", 0x0200, true)];
+
+        let mut errors = vec![];
+        let images = link(&[main, print], &layout, &mut errors);
+        assert_eq!(errors, vec![]);
+        // `print` goes after `main` in `CODE`, and the branch goes back 5 bytes
+        assert_eq!(images[0], vec![0x20, 0x07, 0x80, 0xd0, 0xfb, 0xa9, 0x02, 0x60]);
+        assert_eq!(images[1], vec![0xaa, 0xbb]);
+    }
+
+    #[test]
+    fn link_reports_errors() {
+        let main = parse(
+            "6502OBJ 1\n\
+             SEGMENT CODE 0003\n\
+             RELOC 0 0000 BYTE sym:PRNT\n\
+             RELOC 0 0001 BRANCH seg:0 200 ADD\n\
+             SEGMENT ZP 0003\n\
+             RESERVE 1 0000 0002\n\
+             IMPORT PRNT\n",
+        );
+        let print = parse("6502OBJ 1\nSEGMENT CODE 0000\nEXPORT PRINT seg:0\n");
+        let layout = [area("CODE", 0x8000, true), area("ZP", 0, false)];
+
+        let mut errors = vec![];
+        link(&[main, print], &layout, &mut errors);
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(messages, vec![
+            "segment `ZP` is not output so it can only reserve space",
+            "undefined symbol `PRNT`",
+            "branch target out of range",
+        ]);
+        assert!(matches!(&errors[1], AssemblyError::UndefinedSymbol { suggestion: Some(s), .. } if s == "PRINT"));
+    }
+}
@@ -0,0 +1,1135 @@
+use crate::charset::Encoding;
+use crate::expr::{BinaryOp, Expr, UnaryOp};
+use crate::error::AssemblyError;
+use crate::lexer::{unquote, Quoted, Span, Token, TokenKind};
+use crate::{AddressSize, OPS};
+
+/// The operand of an instruction, named after the syntax it was written with
+/// since the size of the address is not known until symbols are resolved.
+#[derive(Clone)]
+pub enum Operand {
+    Implied,
+    Accumulator,
+    /// `#value`
+    Immediate(Expr),
+    /// `(address)`
+    Indirect(Expr),
+    /// `(address,X)`
+    IndexedIndirect(Expr),
+    /// `(address),Y`
+    IndirectIndexed(Expr),
+    /// `address,X`
+    XIndexed(Expr),
+    /// `address,Y`
+    YIndexed(Expr),
+    /// `address`
+    Direct(Expr),
+}
+
+impl Operand {
+    /// The address or value of the operand, if it has one
+    pub fn expr(&self) -> Option<&Expr> {
+        match self {
+            Operand::Implied | Operand::Accumulator => None,
+            Operand::Immediate(expr)
+            | Operand::Indirect(expr)
+            | Operand::IndexedIndirect(expr)
+            | Operand::IndirectIndexed(expr)
+            | Operand::XIndexed(expr)
+            | Operand::YIndexed(expr)
+            | Operand::Direct(expr) => Some(expr),
+        }
+    }
+
+    pub fn expr_mut(&mut self) -> Option<&mut Expr> {
+        match self {
+            Operand::Implied | Operand::Accumulator => None,
+            Operand::Immediate(expr)
+            | Operand::Indirect(expr)
+            | Operand::IndexedIndirect(expr)
+            | Operand::IndirectIndexed(expr)
+            | Operand::XIndexed(expr)
+            | Operand::YIndexed(expr)
+            | Operand::Direct(expr) => Some(expr),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Instruction {
+    pub mnemonic: String,
+    /// Operand size forced with a `.B` or `.W` suffix on the opcode
+    pub size: Option<AddressSize>,
+    pub operand: Operand,
+    /// The operand as it was written, used for the debug listing
+    pub text: String,
+    pub operand_span: Span,
+}
+
+/// How each value of a data directive is stored
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DataWidth {
+    /// `.BYTE`, `.DB` and the string directives
+    Byte,
+    /// `.WORD` and `.DW`, low byte first
+    Word,
+    /// `.DBYT`, high byte first
+    BigEndianWord,
+}
+
+impl DataWidth {
+    pub fn bytes(self) -> u16 {
+        match self {
+            DataWidth::Byte => 1,
+            DataWidth::Word | DataWidth::BigEndianWord => 2,
+        }
+    }
+}
+
+/// A directive that places data directly in the output
+#[derive(Clone)]
+pub struct Data {
+    pub directive: String,
+    pub width: DataWidth,
+    /// Each value and where it was written, strings are split into one
+    /// character literal per byte
+    pub items: Vec<(Expr, Span)>,
+    /// The values as they were written, used for the debug listing
+    pub text: String,
+}
+
+/// A directive that skips ahead, filling the space it leaves
+#[derive(Clone)]
+pub struct Space {
+    pub directive: String,
+    /// The number of bytes to skip, or the boundary to pad to for `.ALIGN`
+    pub size: (Expr, Span),
+    /// The value to fill with, zero when left out
+    pub fill: Option<(Expr, Span)>,
+    /// The arguments as they were written, used for the debug listing
+    pub text: String,
+}
+
+/// A file whose bytes are placed directly in the output
+#[derive(Clone)]
+pub struct Binary {
+    pub path: String,
+    /// Where to start reading the file, from the start when left out
+    pub offset: Option<(Expr, Span)>,
+    /// How many bytes to read, up to the end of the file when left out
+    pub length: Option<(Expr, Span)>,
+    /// The bytes of the file, read once the path is resolved
+    pub bytes: Vec<u8>,
+    /// The arguments as they were written, used for the debug listing
+    pub text: String,
+}
+
+#[derive(Clone)]
+pub enum StatementKind {
+    /// A line with nothing but a label or comment
+    Empty,
+    /// `name = value`
+    Define(String, Expr),
+    /// `* = address` or `ORG address`
+    Origin(Expr),
+    Instruction(Instruction),
+    /// `.BYTE`, `.WORD`, `.TEXT` and the like
+    Data(Data),
+    /// `.RES`, `.DS` or `.FILL`
+    Reserve(Space),
+    /// `.ALIGN boundary`
+    Align(Space),
+    /// `.INCLUDE "file"`
+    Include(String),
+    /// `.INCBIN "file"`, optionally followed by an offset and length
+    IncludeBinary(Binary),
+    /// `.IF condition`
+    If(Expr),
+    /// `.IFDEF name`
+    IfDef(String),
+    /// `.IFNDEF name`
+    IfNDef(String),
+    /// `.ELSEIF condition`
+    ElseIf(Expr),
+    Else,
+    EndIf,
+    /// `.CHARSET name`, the encoding for the character literals that follow
+    Charset(Encoding),
+    /// `.CHARMAP char, code`, remaps a single character in the current charset
+    CharMap(Expr, Expr),
+    /// `.MACRO name param, ...`, the lines up to `.ENDMACRO` are kept to be
+    /// assembled wherever the macro is used
+    Macro(String, Vec<String>),
+    /// `.ENDMACRO` or `.ENDM`
+    EndMacro,
+    /// A line using a macro
+    MacroCall(MacroCall),
+    /// `.REPEAT count` or `.REPEAT count, var`, the lines up to `.ENDREP` are
+    /// assembled `count` times with `var` counting up from zero
+    Repeat(Expr, Option<String>),
+    EndRepeat,
+    /// `.ASSERT condition` or `.ASSERT condition, "message"`, checked once
+    /// every label has its final address
+    Assert(Expr, Option<String>),
+    /// `.ERROR "message"`
+    Error(String),
+    /// `.WARNING "message"`
+    Warning(String),
+    /// `.SEGMENT "name"`, the lines that follow are placed in the area of
+    /// memory the layout gives the segment
+    Segment(String),
+    /// `.IMPORT name, ...`, symbols another object file exports, which are
+    /// only known once the object files are linked
+    Import(Vec<String>),
+    /// `.EXPORT name, ...`, labels and defines other object files can import
+    Export(Vec<String>),
+}
+
+/// A value given to a macro
+#[derive(Clone)]
+pub struct Argument {
+    /// The parameter and where it was written, for arguments given as `param=value`
+    pub name: Option<(String, Span)>,
+    pub value: Expr,
+    pub span: Span,
+}
+
+/// A macro along with the values for its parameters
+#[derive(Clone)]
+pub struct MacroCall {
+    pub name: String,
+    pub args: Vec<Argument>,
+}
+
+/// A single line of the program
+#[derive(Clone)]
+pub struct Statement {
+    /// The label declared at the start of the line and where it is
+    pub label: Option<(String, Span)>,
+    pub kind: StatementKind,
+    pub span: Span,
+}
+
+impl Statement {
+    /// Every expression in the statement along with where it was written
+    pub fn exprs_mut(&mut self) -> Vec<(&mut Expr, Span)> {
+        let span = self.span;
+        match &mut self.kind {
+            StatementKind::Define(_, expr)
+            | StatementKind::Origin(expr)
+            | StatementKind::If(expr)
+            | StatementKind::ElseIf(expr)
+            | StatementKind::Repeat(expr, _)
+            | StatementKind::Assert(expr, _) => vec![(expr, span)],
+            StatementKind::Instruction(instruction) => {
+                let span = instruction.operand_span;
+                instruction.operand.expr_mut().map(|expr| (expr, span)).into_iter().collect()
+            }
+            StatementKind::Data(data) => data.items.iter_mut().map(|(expr, span)| (expr, *span)).collect(),
+            StatementKind::Reserve(space) | StatementKind::Align(space) => {
+                let (size, size_span) = &mut space.size;
+                let mut exprs = vec![(size, *size_span)];
+                exprs.extend(spanned(&mut space.fill));
+                exprs
+            }
+            StatementKind::IncludeBinary(binary) => spanned(&mut binary.offset).into_iter().chain(spanned(&mut binary.length)).collect(),
+            StatementKind::CharMap(from, to) => vec![(from, span), (to, span)],
+            StatementKind::MacroCall(call) => call.args.iter_mut().map(|arg| (&mut arg.value, arg.span)).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Every span in the statement, including those of its label and expressions
+    pub fn spans_mut(&mut self) -> Vec<&mut Span> {
+        let mut spans = vec![&mut self.span];
+        spans.extend(self.label.as_mut().map(|(_, span)| span));
+        match &mut self.kind {
+            StatementKind::Instruction(instruction) => spans.push(&mut instruction.operand_span),
+            StatementKind::Data(data) => spans.extend(data.items.iter_mut().map(|(_, span)| span)),
+            StatementKind::Reserve(space) | StatementKind::Align(space) => {
+                spans.push(&mut space.size.1);
+                spans.extend(space.fill.as_mut().map(|(_, span)| span));
+            }
+            StatementKind::IncludeBinary(binary) => {
+                spans.extend(binary.offset.as_mut().map(|(_, span)| span));
+                spans.extend(binary.length.as_mut().map(|(_, span)| span));
+            }
+            StatementKind::MacroCall(call) => {
+                for arg in &mut call.args {
+                    spans.push(&mut arg.span);
+                    spans.extend(arg.name.as_mut().map(|(_, span)| span));
+                }
+            }
+            _ => {}
+        }
+        spans
+    }
+}
+
+/// An optional expression along with where it was written
+fn spanned(value: &mut Option<(Expr, Span)>) -> Option<(&mut Expr, Span)> {
+    value.as_mut().map(|(expr, span)| (expr, *span))
+}
+
+/// Every directive, names starting with `.` are reserved for these
+pub const DIRECTIVES: [&str; 33] = [
+    ".BYTE", ".DB", ".WORD", ".DW", ".DBYT", ".TEXT", ".ASCII", ".ASCIIZ", ".CHARSET", ".CHARMAP", ".RES", ".DS",
+    ".FILL", ".ALIGN", ".INCLUDE", ".INCBIN", ".IF", ".IFDEF", ".IFNDEF", ".ELSEIF", ".ELSE", ".ENDIF", ".MACRO",
+    ".ENDMACRO", ".ENDM", ".REPEAT", ".ENDREP", ".ASSERT", ".ERROR", ".WARNING", ".SEGMENT",
+    ".IMPORT", ".EXPORT",
+];
+
+/// Returns if the name is an opcode, directive or `ORG` rather than a label,
+/// these are matched regardless of case
+pub fn is_mnemonic(name: &str) -> bool {
+    let name = name.to_uppercase();
+    name == "ORG" || name.starts_with('.') || OPS.contains(&split_size(&name).0)
+}
+
+/// Returns if a token is the given register or keyword written in any case
+fn is_keyword(kind: &TokenKind, keyword: &str) -> bool {
+    matches!(kind, TokenKind::Ident(name) if name.eq_ignore_ascii_case(keyword))
+}
+
+/// Splits a `.B` (zero page) or `.W` (absolute) size suffix off an opcode
+fn split_size(name: &str) -> (&str, Option<AddressSize>) {
+    match name.rsplit_once('.') {
+        Some((op, "B")) => (op, Some(AddressSize::U8)),
+        Some((op, "W")) => (op, Some(AddressSize::U16)),
+        _ => (name, None),
+    }
+}
+
+/// Records a syntax error. Only the first error on a line is reported since any
+/// others, including those from the lexer, tend to be caused by it.
+fn report(errors: &mut Vec<AssemblyError>, error: AssemblyError) {
+    let span = error.span();
+    if !errors.iter().any(|r| r.span().file == span.file && r.span().line == span.line) {
+        errors.push(error);
+    }
+}
+
+/// Parses tokens one line at a time, so that macros defined on earlier lines
+/// can be told apart from labels
+pub struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    /// Source lines, used to recover the text of operands
+    lines: Vec<&'a str>,
+    /// Whether labels and defines differing only in case are different symbols
+    case_sensitive: bool,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(tokens: &'a [Token], code: &'a str, case_sensitive: bool) -> Parser<'a> {
+        Parser {
+            tokens,
+            pos: 0,
+            lines: code.lines().collect(),
+            case_sensitive,
+        }
+    }
+
+    /// Parses the next line, returning `None` at the end of the tokens. Lines
+    /// with errors are skipped, keeping only their label, and the errors recorded.
+    pub fn next_statement(&mut self, is_macro: &dyn Fn(&str) -> bool, errors: &mut Vec<AssemblyError>) -> Option<Statement> {
+        while self.pos < self.tokens.len() {
+            let start = self.pos;
+            match self.statement(is_macro) {
+                Ok(statement) => return Some(statement),
+                Err(e) => {
+                    let span = e.span();
+                    report(errors, e);
+                    self.skip_line();
+                    let label = self.label_at(start, is_macro).map(|label| (label, self.tokens[start].span));
+                    // A block directive with an error still opens or closes its
+                    // block so that the directives after it stay matched
+                    if let Some(kind) = self.block_directive(start, is_macro) {
+                        return Some(Statement { label, kind, span });
+                    }
+                    if label.is_some() {
+                        return Some(Statement { label, kind: StatementKind::Empty, span });
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Skips lines up to the next one that opens, continues or closes an `.IF`
+    /// block, for branches that are not being assembled
+    pub fn next_block_directive(&mut self, errors: &mut Vec<AssemblyError>) -> Option<Statement> {
+        let not_macro = |_: &str| false;
+        while self.pos < self.tokens.len() {
+            if self.block_directive(self.pos, &not_macro).is_some() {
+                return self.next_statement(&not_macro, errors);
+            }
+            self.skip_line();
+        }
+        None
+    }
+
+    /// The line number of the next token, or `None` at the end of the tokens
+    pub fn line(&self) -> Option<usize> {
+        self.tokens.get(self.pos).map(|token| token.span.line)
+    }
+
+    /// Looks at a token ahead of the current one, every line ends with a newline
+    /// so this stops at the end of the tokens
+    fn peek_at(&self, offset: usize) -> &'a Token {
+        let last = self.tokens.len() - 1;
+        &self.tokens[(self.pos + offset).min(last)]
+    }
+
+    fn peek(&self) -> &'a TokenKind {
+        &self.peek_at(0).kind
+    }
+
+    fn next(&mut self) -> &'a Token {
+        let token = self.peek_at(0);
+        self.pos += 1;
+        token
+    }
+
+    fn at_line_end(&self) -> bool {
+        matches!(self.peek(), TokenKind::Newline | TokenKind::Comment(_))
+    }
+
+    /// The name of a label or define as it is stored in the symbol table
+    fn symbol(&self, name: &str) -> String {
+        if self.case_sensitive {
+            name.to_string()
+        } else {
+            name.to_uppercase()
+        }
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, AssemblyError> {
+        Err(AssemblyError::Syntax {
+            message,
+            span: self.peek_at(0).span,
+        })
+    }
+
+    /// Returns the label a line starting at `pos` declares, if any
+    fn label_at(&self, pos: usize, is_macro: &dyn Fn(&str) -> bool) -> Option<String> {
+        match (&self.tokens[pos].kind, self.tokens.get(pos + 1).map(|t| &t.kind)) {
+            (TokenKind::Ident(name), Some(TokenKind::Colon)) if !is_mnemonic(name) => Some(self.symbol(name)),
+            (TokenKind::Ident(name), Some(TokenKind::Ident(_)) | Some(TokenKind::Newline) | Some(TokenKind::Comment(_)))
+                if !is_mnemonic(name) && !is_macro(&self.symbol(name)) => Some(self.symbol(name)),
+            _ => None,
+        }
+    }
+
+    /// Returns the `.IF` block directive a line starting at `pos` has, with
+    /// placeholders for its operands
+    fn block_directive(&self, pos: usize, is_macro: &dyn Fn(&str) -> bool) -> Option<StatementKind> {
+        let label_len = match self.label_at(pos, is_macro) {
+            Some(_) if self.tokens[pos + 1].kind == TokenKind::Colon => 2,
+            Some(_) => 1,
+            None => 0,
+        };
+        let name = match self.tokens.get(pos + label_len).map(|token| &token.kind) {
+            Some(TokenKind::Ident(name)) => name.to_uppercase(),
+            _ => return None,
+        };
+        Some(match name.as_str() {
+            ".IF" => StatementKind::If(Expr::Number(0, AddressSize::U8)),
+            ".IFDEF" => StatementKind::IfDef(String::new()),
+            ".IFNDEF" => StatementKind::IfNDef(String::new()),
+            ".ELSEIF" => StatementKind::ElseIf(Expr::Number(0, AddressSize::U8)),
+            ".ELSE" => StatementKind::Else,
+            ".ENDIF" => StatementKind::EndIf,
+            _ => return None,
+        })
+    }
+
+    /// Moves past the end of the current line
+    fn skip_line(&mut self) {
+        while self.pos < self.tokens.len() && self.next().kind != TokenKind::Newline {}
+    }
+
+    /// The span of the most recently consumed token
+    fn last_span(&self) -> Span {
+        self.tokens[self.pos.saturating_sub(1)].span
+    }
+
+    fn statement(&mut self, is_macro: &dyn Fn(&str) -> bool) -> Result<Statement, AssemblyError> {
+        let start = self.peek_at(0).span;
+        let mut label = None;
+
+        let kind = match (self.peek(), &self.peek_at(1).kind) {
+            (TokenKind::Operator("*"), TokenKind::Operator("=")) => {
+                self.pos += 2;
+                StatementKind::Origin(self.expression()?)
+            }
+            (TokenKind::Ident(name), TokenKind::Operator("=")) => {
+                self.pos += 2;
+                StatementKind::Define(self.symbol(name), self.expression()?)
+            }
+            _ => {
+                if let TokenKind::Ident(name) = self.peek() {
+                    // A macro used at the start of a line is not a label
+                    let call = is_macro(&self.symbol(name)) && self.peek_at(1).kind != TokenKind::Colon;
+                    if !is_mnemonic(name) && !call {
+                        let token = self.next();
+                        label = Some((self.symbol(name), token.span));
+                        match self.peek() {
+                            TokenKind::Colon => self.pos += 1,
+                            // Without a colon a name followed by an operand was
+                            // meant to be an opcode
+                            TokenKind::Ident(_) | TokenKind::Newline | TokenKind::Comment(_) => {}
+                            _ => {
+                                return Err(AssemblyError::UnknownMnemonic {
+                                    name: name.to_uppercase(),
+                                    span: token.span,
+                                })
+                            }
+                        }
+                    }
+                }
+
+                match self.peek() {
+                    TokenKind::Ident(name) => {
+                        let keyword = name.to_uppercase();
+                        if keyword == "ORG" {
+                            self.pos += 1;
+                            StatementKind::Origin(self.expression()?)
+                        } else if keyword.starts_with('.') {
+                            self.directive(&keyword)?
+                        } else if is_mnemonic(&keyword) {
+                            self.pos += 1;
+                            StatementKind::Instruction(self.instruction(&keyword)?)
+                        } else if is_macro(&self.symbol(name)) {
+                            self.pos += 1;
+                            StatementKind::MacroCall(self.macro_call(self.symbol(name))?)
+                        } else {
+                            return Err(AssemblyError::UnknownMnemonic {
+                                name: keyword,
+                                span: self.peek_at(0).span,
+                            });
+                        }
+                    }
+                    _ if self.at_line_end() => StatementKind::Empty,
+                    other => return self.error(format!("expected an opcode but found {}", other)),
+                }
+            }
+        };
+
+        let span = start.to(self.last_span());
+        if let TokenKind::Comment(_) = self.peek() {
+            self.pos += 1;
+        }
+        match self.next().kind {
+            TokenKind::Newline => Ok(Statement { label, kind, span }),
+            ref other => {
+                self.pos -= 1;
+                self.error(format!("expected end of line but found {}", other))
+            }
+        }
+    }
+
+    fn instruction(&mut self, name: &str) -> Result<Instruction, AssemblyError> {
+        let (mnemonic, size) = split_size(name);
+        let start = self.peek_at(0).span;
+        let operand = self.operand()?;
+
+        let (text, operand_span) = if let Operand::Implied = operand {
+            (String::new(), start)
+        } else {
+            let span = start.to(self.last_span());
+            (self.text(span), span)
+        };
+
+        Ok(Instruction {
+            mnemonic: mnemonic.to_string(),
+            size,
+            operand,
+            text,
+            operand_span,
+        })
+    }
+
+    fn directive(&mut self, name: &str) -> Result<StatementKind, AssemblyError> {
+        let span = self.next().span;
+        match name {
+            ".BYTE" | ".DB" | ".TEXT" | ".ASCII" => Ok(StatementKind::Data(self.data(name, DataWidth::Byte)?)),
+            ".ASCIIZ" => {
+                let mut data = self.data(name, DataWidth::Byte)?;
+                data.items.push((Expr::Number(0, AddressSize::U8), self.last_span()));
+                Ok(StatementKind::Data(data))
+            }
+            ".WORD" | ".DW" => Ok(StatementKind::Data(self.data(name, DataWidth::Word)?)),
+            ".DBYT" => Ok(StatementKind::Data(self.data(name, DataWidth::BigEndianWord)?)),
+            ".CHARSET" => match self.peek() {
+                TokenKind::Ident(charset) => {
+                    let span = self.next().span;
+                    Encoding::from_name(&charset.to_uppercase())
+                        .map(StatementKind::Charset)
+                        .ok_or_else(|| AssemblyError::UnknownCharset { name: charset.clone(), span })
+                }
+                other => self.error(format!("expected a character set but found {}", other)),
+            },
+            ".CHARMAP" => {
+                let from = self.expression()?;
+                self.expect_comma()?;
+                Ok(StatementKind::CharMap(from, self.expression()?))
+            }
+            ".RES" | ".DS" | ".FILL" => Ok(StatementKind::Reserve(self.space(name)?)),
+            ".ALIGN" => Ok(StatementKind::Align(self.space(name)?)),
+            ".IF" => Ok(StatementKind::If(self.expression()?)),
+            ".IFDEF" => Ok(StatementKind::IfDef(self.name()?)),
+            ".IFNDEF" => Ok(StatementKind::IfNDef(self.name()?)),
+            ".ELSEIF" => Ok(StatementKind::ElseIf(self.expression()?)),
+            ".ELSE" => Ok(StatementKind::Else),
+            ".ENDIF" => Ok(StatementKind::EndIf),
+            ".MACRO" => {
+                if let TokenKind::Ident(name) = self.peek() {
+                    if is_mnemonic(name) {
+                        return self.error(format!("`{}` is an opcode or directive and can not name a macro", name));
+                    }
+                }
+                let name = self.name()?;
+                let mut params: Vec<String> = vec![];
+                while !self.at_line_end() {
+                    if !params.is_empty() {
+                        self.expect_comma()?;
+                    }
+                    let span = self.peek_at(0).span;
+                    let param = self.name()?;
+                    if params.contains(&param) {
+                        return Err(AssemblyError::Syntax { message: format!("parameter `{}` is listed twice", param), span });
+                    }
+                    params.push(param);
+                }
+                Ok(StatementKind::Macro(name, params))
+            }
+            ".ENDMACRO" | ".ENDM" => Ok(StatementKind::EndMacro),
+            ".REPEAT" => {
+                let count = self.expression()?;
+                let var = match self.peek() {
+                    TokenKind::Comma => {
+                        self.pos += 1;
+                        Some(self.name()?)
+                    }
+                    _ => None,
+                };
+                Ok(StatementKind::Repeat(count, var))
+            }
+            ".ENDREP" => Ok(StatementKind::EndRepeat),
+            ".ASSERT" => {
+                let condition = self.expression()?;
+                let message = match self.peek() {
+                    TokenKind::Comma => {
+                        self.pos += 1;
+                        Some(self.string("a message")?)
+                    }
+                    _ => None,
+                };
+                Ok(StatementKind::Assert(condition, message))
+            }
+            ".ERROR" => Ok(StatementKind::Error(self.string("a message")?)),
+            ".WARNING" => Ok(StatementKind::Warning(self.string("a message")?)),
+            ".SEGMENT" => Ok(StatementKind::Segment(self.string("a segment name")?)),
+            ".IMPORT" => Ok(StatementKind::Import(self.names()?)),
+            ".EXPORT" => Ok(StatementKind::Export(self.names()?)),
+            ".INCLUDE" => Ok(StatementKind::Include(self.string("a file name")?)),
+            ".INCBIN" => {
+                let start = self.peek_at(0).span;
+                let path = self.string("a file name")?;
+                let mut range = [None, None];
+                for value in &mut range {
+                    if self.peek() != &TokenKind::Comma {
+                        break;
+                    }
+                    self.pos += 1;
+                    *value = Some(self.spanned_expression()?);
+                }
+                let [offset, length] = range;
+                Ok(StatementKind::IncludeBinary(Binary {
+                    path,
+                    offset,
+                    length,
+                    bytes: vec![],
+                    text: self.text(start.to(self.last_span())),
+                }))
+            }
+            _ => Err(AssemblyError::UnknownDirective { name: name.to_string(), span }),
+        }
+    }
+
+    /// Parses a comma separated list of expressions, and strings when storing bytes
+    fn data(&mut self, directive: &str, width: DataWidth) -> Result<Data, AssemblyError> {
+        let start = self.peek_at(0).span;
+        let mut items = vec![];
+
+        loop {
+            let item_start = self.peek_at(0).span;
+            match self.peek() {
+                TokenKind::Str(_) if width != DataWidth::Byte => {
+                    return self.error(format!("strings can not be used with `{}`", directive));
+                }
+                TokenKind::Str(text) => {
+                    self.pos += 1;
+                    items.extend(text.iter().map(|quoted| match quoted {
+                        Quoted::Char(c) => (Expr::Char(*c), item_start),
+                        Quoted::Byte(byte) => (Expr::Number(*byte as i64, AddressSize::U8), item_start),
+                    }));
+                }
+                _ => items.push(self.spanned_expression()?),
+            }
+
+            match self.peek() {
+                TokenKind::Comma => self.pos += 1,
+                _ if self.at_line_end() => break,
+                other => return self.error(format!("expected ',' or end of line but found {}", other)),
+            }
+        }
+
+        Ok(Data {
+            directive: directive.to_string(),
+            width,
+            items,
+            text: self.text(start.to(self.last_span())),
+        })
+    }
+
+    /// Parses the comma separated arguments of a macro, each of which can be
+    /// given by position or as `param=value`
+    fn macro_call(&mut self, name: String) -> Result<MacroCall, AssemblyError> {
+        let mut args = vec![];
+
+        while !self.at_line_end() {
+            if !args.is_empty() {
+                self.expect_comma()?;
+            }
+            let param = match (self.peek(), &self.peek_at(1).kind) {
+                (TokenKind::Ident(param), TokenKind::Operator("=")) => {
+                    let span = self.next().span;
+                    self.pos += 1;
+                    Some((self.symbol(param), span))
+                }
+                _ => None,
+            };
+            let (value, span) = self.spanned_expression()?;
+            args.push(Argument { name: param, value, span });
+        }
+
+        Ok(MacroCall { name, args })
+    }
+
+    /// Parses the name of a label or define
+    fn name(&mut self) -> Result<String, AssemblyError> {
+        match self.peek() {
+            TokenKind::Ident(name) => {
+                self.pos += 1;
+                Ok(self.symbol(name))
+            }
+            other => self.error(format!("expected a name but found {}", other)),
+        }
+    }
+
+    /// Parses a comma separated list of at least one name
+    fn names(&mut self) -> Result<Vec<String>, AssemblyError> {
+        let mut names = vec![self.name()?];
+        while !self.at_line_end() {
+            self.expect_comma()?;
+            names.push(self.name()?);
+        }
+        Ok(names)
+    }
+
+    /// Parses a string, `what` describes it in the error if there is none
+    fn string(&mut self, what: &str) -> Result<String, AssemblyError> {
+        match self.peek() {
+            TokenKind::Str(text) => {
+                self.pos += 1;
+                Ok(unquote(text))
+            }
+            other => self.error(format!("expected {} in quotes but found {}", what, other)),
+        }
+    }
+
+    /// Parses a size followed by an optional fill value
+    fn space(&mut self, directive: &str) -> Result<Space, AssemblyError> {
+        let start = self.peek_at(0).span;
+        let size = self.spanned_expression()?;
+        let fill = match self.peek() {
+            TokenKind::Comma => {
+                self.pos += 1;
+                Some(self.spanned_expression()?)
+            }
+            _ => None,
+        };
+
+        Ok(Space {
+            directive: directive.to_string(),
+            size,
+            fill,
+            text: self.text(start.to(self.last_span())),
+        })
+    }
+
+    /// The source text covered by a span on a single line
+    fn text(&self, span: Span) -> String {
+        let line = self.lines[span.line - 1];
+        line.chars().skip(span.column - 1).take(span.len).collect()
+    }
+
+    fn operand(&mut self) -> Result<Operand, AssemblyError> {
+        if self.at_line_end() {
+            return Ok(Operand::Implied);
+        }
+
+        match self.peek() {
+            kind if is_keyword(kind, "A") && matches!(self.peek_at(1).kind, TokenKind::Newline | TokenKind::Comment(_)) => {
+                self.pos += 1;
+                Ok(Operand::Accumulator)
+            }
+            TokenKind::Operator("#") => {
+                self.pos += 1;
+                Ok(Operand::Immediate(self.expression()?))
+            }
+            TokenKind::LParen => {
+                let start = self.next().span;
+                let inner = self.expression()?;
+
+                if let TokenKind::Comma = self.peek() {
+                    self.pos += 1;
+                    self.register("X")?;
+                    self.expect_rparen()?;
+                    return Ok(Operand::IndexedIndirect(inner));
+                }
+                self.expect_rparen()?;
+
+                match self.peek() {
+                    _ if self.at_line_end() => Ok(Operand::Indirect(inner)),
+                    TokenKind::Comma if is_keyword(&self.peek_at(1).kind, "X") => {
+                        self.pos += 2;
+                        Err(AssemblyError::Syntax {
+                            message: "`(addr),X` is not an addressing mode, use `(addr,X)`".to_string(),
+                            span: start.to(self.last_span()),
+                        })
+                    }
+                    TokenKind::Comma => {
+                        self.pos += 1;
+                        self.register("Y")?;
+                        Ok(Operand::IndirectIndexed(inner))
+                    }
+                    // The parentheses were only grouping part of a larger address
+                    TokenKind::Operator(op) if BinaryOp::from_symbol(op).is_some() => {
+                        let expr = self.binary(inner, 0)?;
+                        self.indexed(expr)
+                    }
+                    other => self.error(format!("expected end of line but found {}", other)),
+                }
+            }
+            _ => {
+                let expr = self.expression()?;
+                self.indexed(expr)
+            }
+        }
+    }
+
+    /// Parses an optional `,X` or `,Y` after an address
+    fn indexed(&mut self, expr: Expr) -> Result<Operand, AssemblyError> {
+        if let TokenKind::Comma = self.peek() {
+            self.pos += 1;
+            match self.peek() {
+                kind if is_keyword(kind, "X") => {
+                    self.pos += 1;
+                    Ok(Operand::XIndexed(expr))
+                }
+                kind if is_keyword(kind, "Y") => {
+                    self.pos += 1;
+                    Ok(Operand::YIndexed(expr))
+                }
+                other => self.error(format!("expected X or Y but found {}", other)),
+            }
+        } else {
+            Ok(Operand::Direct(expr))
+        }
+    }
+
+    fn register(&mut self, register: &str) -> Result<(), AssemblyError> {
+        match self.peek() {
+            kind if is_keyword(kind, register) => {
+                self.pos += 1;
+                Ok(())
+            }
+            other => self.error(format!("expected {} but found {}", register, other)),
+        }
+    }
+
+    fn expect_rparen(&mut self) -> Result<(), AssemblyError> {
+        match self.peek() {
+            TokenKind::RParen => {
+                self.pos += 1;
+                Ok(())
+            }
+            other => self.error(format!("expected ')' but found {}", other)),
+        }
+    }
+
+    fn expect_comma(&mut self) -> Result<(), AssemblyError> {
+        match self.peek() {
+            TokenKind::Comma => {
+                self.pos += 1;
+                Ok(())
+            }
+            other => self.error(format!("expected ',' but found {}", other)),
+        }
+    }
+
+    /// Parses an expression along with the span it was written at
+    fn spanned_expression(&mut self) -> Result<(Expr, Span), AssemblyError> {
+        let start = self.peek_at(0).span;
+        let expr = self.expression()?;
+        Ok((expr, start.to(self.last_span())))
+    }
+
+    fn expression(&mut self) -> Result<Expr, AssemblyError> {
+        let lhs = self.unary()?;
+        self.binary(lhs, 0)
+    }
+
+    /// Precedence climbing over the binary operators following `lhs`
+    fn binary(&mut self, mut lhs: Expr, min_precedence: u8) -> Result<Expr, AssemblyError> {
+        while let TokenKind::Operator(symbol) = self.peek() {
+            let op = match BinaryOp::from_symbol(symbol) {
+                Some(op) if op.precedence() > min_precedence => op,
+                _ => break,
+            };
+            self.pos += 1;
+            let rhs = self.unary()?;
+            let rhs = self.binary(rhs, op.precedence())?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, AssemblyError> {
+        match self.peek() {
+            TokenKind::Number(n, size) => {
+                self.pos += 1;
+                Ok(Expr::Number(*n, *size))
+            }
+            TokenKind::Char(c) => {
+                self.pos += 1;
+                Ok(Expr::Char(*c))
+            }
+            TokenKind::Ident(name) => {
+                self.pos += 1;
+                Ok(Expr::Symbol(self.symbol(name)))
+            }
+            TokenKind::Operator("*") => {
+                self.pos += 1;
+                Ok(Expr::Pc)
+            }
+            TokenKind::Operator("+") => {
+                self.pos += 1;
+                self.unary()
+            }
+            TokenKind::Operator(symbol) => {
+                let op = match *symbol {
+                    "-" => UnaryOp::Negate,
+                    "~" => UnaryOp::Complement,
+                    "!" => UnaryOp::Not,
+                    "<" => UnaryOp::Low,
+                    ">" => UnaryOp::High,
+                    _ => return self.error(format!("expected a value but found '{}'", symbol)),
+                };
+                self.pos += 1;
+                Ok(Expr::Unary(op, Box::new(self.unary()?)))
+            }
+            TokenKind::LParen => {
+                self.pos += 1;
+                let expr = self.expression()?;
+                self.expect_rparen()?;
+                Ok(expr)
+            }
+            other => self.error(format!("expected a value but found {}", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{lex, Span};
+    use crate::AddressSize;
+
+    /// Parses every line, treating `INC16` as a macro
+    fn parse(code: &str, case_sensitive: bool, errors: &mut Vec<AssemblyError>) -> Vec<Statement> {
+        let tokens = lex(code, 0, errors);
+        let mut parser = Parser::new(&tokens, code, case_sensitive);
+        let mut statements = vec![];
+        while let Some(statement) = parser.next_statement(&|name| name == "INC16", errors) {
+            statements.push(statement);
+        }
+        statements
+    }
+
+    fn statement(line: &str) -> Result<Statement, AssemblyError> {
+        let mut errors = vec![];
+        let mut statements = parse(line, false, &mut errors);
+        match errors.pop() {
+            Some(e) => Err(e),
+            None => Ok(statements.remove(0)),
+        }
+    }
+
+    fn error(line: &str) -> String {
+        match statement(line) {
+            Err(e) => e.to_string(),
+            Ok(_) => panic!("`{}` should not parse", line),
+        }
+    }
+
+    /// Parses the value of a define
+    fn expr(text: &str) -> Expr {
+        match statement(&format!("X = {}", text)).map(|statement| statement.kind) {
+            Ok(StatementKind::Define(_, expr)) => expr,
+            _ => panic!("`{}` is not an expression", text),
+        }
+    }
+
+    fn operand(line: &str) -> Operand {
+        match statement(line).map(|statement| statement.kind) {
+            Ok(StatementKind::Instruction(instruction)) => instruction.operand,
+            _ => panic!("`{}` is not an instruction", line),
+        }
+    }
+
+    fn num(n: i64) -> Box<Expr> {
+        Box::new(Expr::Number(n, AddressSize::U8))
+    }
+
+    fn binary(op: BinaryOp, l: Box<Expr>, r: Box<Expr>) -> Box<Expr> {
+        Box::new(Expr::Binary(op, l, r))
+    }
+
+    #[test]
+    fn binary_operators_follow_precedence() {
+        assert_eq!(expr("1+2*3"), *binary(BinaryOp::Add, num(1), binary(BinaryOp::Multiply, num(2), num(3))));
+        assert_eq!(expr("1*2+3"), *binary(BinaryOp::Add, binary(BinaryOp::Multiply, num(1), num(2)), num(3)));
+        // Operators of the same precedence group to the left
+        assert_eq!(expr("8-4-2"), *binary(BinaryOp::Subtract, binary(BinaryOp::Subtract, num(8), num(4)), num(2)));
+        assert_eq!(expr("(1+2)*3"), *binary(BinaryOp::Multiply, binary(BinaryOp::Add, num(1), num(2)), num(3)));
+        assert_eq!(expr("1|2==2"), *binary(BinaryOp::Or, num(1), binary(BinaryOp::Equal, num(2), num(2))));
+        assert_eq!(expr("1<<2+1"), *binary(BinaryOp::ShiftLeft, num(1), binary(BinaryOp::Add, num(2), num(1))));
+    }
+
+    #[test]
+    fn unary_operators_bind_tightest() {
+        assert_eq!(expr("-1+2"), *binary(BinaryOp::Add, Box::new(Expr::Unary(UnaryOp::Negate, num(1))), num(2)));
+        assert_eq!(expr(">$1234"), Expr::Unary(UnaryOp::High, Box::new(Expr::Number(0x1234, AddressSize::U16))));
+        assert_eq!(expr("*+2"), *binary(BinaryOp::Add, Box::new(Expr::Pc), num(2)));
+    }
+
+    #[test]
+    fn characters_are_values() {
+        assert_eq!(expr("'0'+5"), *binary(BinaryOp::Add, Box::new(Expr::Char('0')), num(5)));
+        assert_eq!(expr("'a'"), Expr::Char('a'));
+    }
+
+    #[test]
+    fn addressing_modes() {
+        assert!(matches!(operand("NOP"), Operand::Implied));
+        assert!(matches!(operand("ASL A"), Operand::Accumulator));
+        assert!(matches!(operand("LDA #1"), Operand::Immediate(_)));
+        assert!(matches!(operand("JMP ($1234)"), Operand::Indirect(_)));
+        assert!(matches!(operand("LDA ($10,X)"), Operand::IndexedIndirect(_)));
+        assert!(matches!(operand("LDA ($10),Y"), Operand::IndirectIndexed(_)));
+        assert!(matches!(operand("LDA $10,X"), Operand::XIndexed(_)));
+        assert!(matches!(operand("LDX $10,Y"), Operand::YIndexed(_)));
+        assert!(matches!(operand("LDA (1+2)*3"), Operand::Direct(_)));
+    }
+
+    #[test]
+    fn indirect_addresses_are_only_indexed_by_y() {
+        assert_eq!(error("LDA ($10),X"), "`(addr),X` is not an addressing mode, use `(addr,X)`");
+        assert_eq!(error("LDA ($10),Z"), "expected Y but found 'Z'");
+        assert_eq!(error("LDA ($10) 5"), "expected end of line but found '5'");
+        assert!(matches!(operand("LDA ($10)+1,X"), Operand::XIndexed(_)));
+    }
+
+    #[test]
+    fn labels() {
+        let label = |line| statement(line).unwrap().label.map(|(name, _)| name);
+        assert_eq!(label("LOOP: NOP"), Some("LOOP".to_string()));
+        assert_eq!(label("LOOP NOP"), Some("LOOP".to_string()));
+        assert_eq!(statement("  LOOP: NOP").unwrap().label, Some(("LOOP".to_string(), Span { file: 0, line: 1, column: 3, len: 4, expansion: 0 })));
+        assert!(matches!(statement("LOOP:").unwrap().kind, StatementKind::Empty));
+    }
+
+    #[test]
+    fn symbols_are_uppercased_unless_case_sensitive() {
+        let code = "loop: lda Value,x";
+        let names = |case_sensitive| {
+            let statement = parse(code, case_sensitive, &mut vec![]).remove(0);
+            match statement.kind {
+                StatementKind::Instruction(instruction) => {
+                    assert_eq!(instruction.mnemonic, "LDA");
+                    assert!(matches!(instruction.operand, Operand::XIndexed(_)));
+                    (statement.label.unwrap().0, instruction.operand.expr().unwrap().to_string())
+                }
+                _ => panic!("`{}` is not an instruction", code),
+            }
+        };
+        assert_eq!(names(false), ("LOOP".to_string(), "VALUE".to_string()));
+        assert_eq!(names(true), ("loop".to_string(), "Value".to_string()));
+    }
+
+    #[test]
+    fn macros_are_used_in_place_of_opcodes() {
+        let statements = parse("INC16 PTR, step=2\nINC16: NOP\nNEXT: INC16", false, &mut vec![]);
+        match &statements[0].kind {
+            StatementKind::MacroCall(call) => {
+                assert_eq!(call.name, "INC16");
+                assert_eq!(call.args.len(), 2);
+                assert_eq!(call.args[0].name, None);
+                assert_eq!(call.args[0].value, Expr::Symbol("PTR".to_string()));
+                assert_eq!(call.args[1].name.as_ref().map(|(name, _)| name.as_str()), Some("STEP"));
+                assert_eq!(call.args[1].value, Expr::Number(2, AddressSize::U8));
+            }
+            _ => panic!("expected a macro call"),
+        }
+        // Followed by a colon the name is a label instead
+        assert_eq!(statements[1].label.as_ref().map(|(name, _)| name.as_str()), Some("INC16"));
+        assert!(matches!(&statements[2].kind, StatementKind::MacroCall(call) if call.args.is_empty()));
+    }
+
+    #[test]
+    fn macro_definitions() {
+        match statement(".MACRO copy from, to").unwrap().kind {
+            StatementKind::Macro(name, params) => {
+                assert_eq!(name, "COPY");
+                assert_eq!(params, vec!["FROM".to_string(), "TO".to_string()]);
+            }
+            _ => panic!("expected a macro definition"),
+        }
+        assert!(matches!(statement(".ENDM").unwrap().kind, StatementKind::EndMacro));
+    }
+
+    #[test]
+    fn lines_with_errors_keep_their_label() {
+        let code = "START: LDA #1 2\nFOO #1\nNOP";
+        let mut errors = vec![];
+        let statements = parse(code, false, &mut errors);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(statements.len(), 2);
+        assert_eq!(statements[0].label.as_ref().map(|(name, _)| name.as_str()), Some("START"));
+        assert!(matches!(statements[0].kind, StatementKind::Empty));
+        assert!(matches!(statements[1].kind, StatementKind::Instruction(_)));
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert_eq!(error("LDA $10,Z"), "expected X or Y but found 'Z'");
+        assert_eq!(error("LDA ($10,Y)"), "expected X but found 'Y'");
+        assert_eq!(error("LDA #1 2"), "expected end of line but found '2'");
+        assert_eq!(error("LOOP: 5"), "expected an opcode but found '5'");
+        assert_eq!(error("FOO #1"), "unknown opcode `FOO`");
+    }
+}
@@ -0,0 +1,67 @@
+mod common;
+
+use common::{assemble, run};
+
+#[test]
+fn true_assertions_pass() {
+    assert_eq!(assemble("*= $1000\nNOP\n.ASSERT * == $1001, \"one byte\""), "* = 00 10 EA");
+}
+
+#[test]
+fn false_assertions_fail_with_their_message() {
+    let run = run("*= $BFFF\nJMP $1234\n.ASSERT * <= $C000, \"code runs into the vectors\"");
+    assert_eq!(run.code, Some(1));
+    let errors = run.errors();
+    assert!(errors.contains("error: assertion failed: code runs into the vectors\n --> prog.asm:3:1"), "{}", errors);
+    assert!(self::run(".ASSERT 0").errors().contains("error: assertion failed\n"));
+}
+
+#[test]
+fn assertions_see_labels_declared_after_them() {
+    let code = "\
+.ASSERT END - START == 3, \"table size\"
+START: .BYTE 1, 2, 3
+END:
+";
+    assert_eq!(assemble(code), "01 02 03");
+    let code = code.replace("== 3", "== 4");
+    assert!(run(&code).errors().contains("error: assertion failed: table size"));
+}
+
+#[test]
+fn errors_fail_the_build() {
+    let run = run("NOP\n.ERROR \"unsupported revision\"");
+    assert_eq!(run.code, Some(1));
+    assert!(run.errors().contains("error: unsupported revision\n --> prog.asm:2:1"));
+    assert_eq!(run.stdout, "");
+}
+
+#[test]
+fn warnings_do_not_fail_the_build() {
+    let run = run("NOP\n.WARNING \"slow path\"");
+    assert_eq!(run.code, Some(0));
+    assert_eq!(run.bytes(), "EA");
+    assert!(run.stderr.contains("warning: slow path\n --> prog.asm:2:1"), "{}", run.stderr);
+}
+
+#[test]
+fn warnings_are_shown_along_with_errors() {
+    let run = run(".WARNING \"first\"\n.ERROR \"second\"");
+    assert_eq!(run.code, Some(1));
+    let errors = run.errors();
+    assert!(errors.contains("warning: first"), "{}", errors);
+    assert!(errors.contains("error: second"), "{}", errors);
+}
+
+#[test]
+fn errors_in_branches_that_are_not_assembled_are_ignored() {
+    let code = "\
+REVISION = 2
+.IF REVISION < 2
+.ERROR \"revision 2 or later is required\"
+.ENDIF
+NOP
+";
+    assert_eq!(assemble(code), "EA");
+    assert!(run(&code.replace("= 2", "= 1")).errors().contains("error: revision 2 or later is required"));
+}
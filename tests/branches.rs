@@ -0,0 +1,51 @@
+mod common;
+
+use common::{assemble, run};
+
+#[test]
+fn backward_branches() {
+    let code = "\
+*= $1000
+LOOP: DEX
+BNE LOOP
+";
+    assert_eq!(assemble(code), "* = 00 10 CA D0 FD");
+}
+
+#[test]
+fn forward_branches() {
+    let code = "\
+*= $1000
+BEQ DONE
+NOP
+DONE: RTS
+";
+    assert_eq!(assemble(code), "* = 00 10 F0 01 EA 60");
+}
+
+#[test]
+fn branches_to_numbers_and_the_pc() {
+    assert_eq!(assemble("*= $1000\nBNE $1010"), "* = 00 10 D0 0E");
+    assert_eq!(assemble("*= $1000\nBCC *"), "* = 00 10 90 FE");
+    assert_eq!(assemble("*= $1000\nBCS *+4"), "* = 00 10 B0 02");
+}
+
+#[test]
+fn branches_reach_from_minus_128_to_127() {
+    let far = "NOP\n".repeat(127);
+    assert_eq!(assemble(&format!("BNE END\n{}END: NOP", far)).split(' ').nth(1), Some("7F"));
+
+    let back = "NOP\n".repeat(126);
+    assert!(assemble(&format!("START: {}BNE START", back)).ends_with("D0 80"));
+}
+
+#[test]
+fn branches_out_of_range_are_reported() {
+    let code = format!("BNE END\n{}END: NOP", "NOP\n".repeat(128));
+    let errors = run(&code).errors().to_string();
+    assert!(errors.contains("error: branch target out of range"), "{}", errors);
+    assert!(errors.contains("the target is 128 bytes away but branches reach -128 to 127"), "{}", errors);
+
+    let code = format!("START: {}BNE START", "NOP\n".repeat(127));
+    assert!(run(&code).errors().contains("the target is -129 bytes away"));
+}
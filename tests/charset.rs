@@ -0,0 +1,90 @@
+mod common;
+
+use common::{assemble, run};
+
+#[test]
+fn ascii_is_the_default() {
+    assert_eq!(assemble(".TEXT \"Hi\""), "48 69");
+}
+
+#[test]
+fn petscii() {
+    let code = "\
+.CHARSET PETSCII
+.TEXT \"Hi\\n\"
+CMP #'a'
+";
+    assert_eq!(assemble(code), "C8 49 0D C9 41");
+}
+
+#[test]
+fn screen_codes() {
+    assert_eq!(assemble(".CHARSET SCREEN\n.TEXT \"@Ab1\""), "00 41 02 31");
+}
+
+#[test]
+fn atascii() {
+    assert_eq!(assemble(".CHARSET ATASCII\n.TEXT \"Ab\\n\""), "41 62 9B");
+}
+
+#[test]
+fn charsets_apply_to_the_lines_after_them() {
+    let code = "\
+.BYTE 'a'
+.CHARSET SCREEN
+.BYTE 'a'
+.CHARSET ASCII
+.BYTE 'a'
+";
+    assert_eq!(assemble(code), "61 01 61");
+}
+
+#[test]
+fn charmap_remaps_single_characters() {
+    let code = "\
+.CHARSET ASCII
+.CHARMAP 'A', $C1
+.TEXT \"AB\"
+.CHARSET ASCII
+.TEXT \"A\"
+";
+    assert_eq!(assemble(code), "C1 42 41");
+}
+
+#[test]
+fn defines_use_the_charset_of_their_own_line() {
+    let code = "\
+LETTER = 'a'
+.CHARSET SCREEN
+.BYTE LETTER
+";
+    assert_eq!(assemble(code), "61");
+}
+
+#[test]
+fn unknown_charsets_are_reported() {
+    let errors = run(".CHARSET EBCDIC").errors().to_string();
+    assert!(errors.contains("error: unknown character set `EBCDIC`"), "{}", errors);
+    assert!(errors.contains("the character sets are: ASCII, PETSCII, SCREEN, ATASCII"), "{}", errors);
+}
+
+#[test]
+fn unmapped_characters_are_reported() {
+    let errors = run(".CHARSET PETSCII\n.TEXT \"{\"").errors().to_string();
+    assert!(errors.contains("error: character '{' has no code in PETSCII"), "{}", errors);
+    assert!(errors.contains("use `.CHARMAP` to give it a code"), "{}", errors);
+}
+
+#[test]
+fn hex_escapes_bypass_the_charset() {
+    assert_eq!(assemble(".CHARSET PETSCII\n.TEXT \"a\\x41\""), "41 41");
+    assert_eq!(assemble(".CHARSET SCREEN\n.BYTE '\\xC1', 'a'"), "C1 01");
+    assert_eq!(assemble(".TEXT \"\\xE9\""), "E9");
+}
+
+#[test]
+fn characters_over_7f_need_a_code_outside_ascii() {
+    assert_eq!(assemble(".TEXT \"é\""), "E9");
+    let errors = run(".CHARSET PETSCII\n.TEXT \"é\"").errors().to_string();
+    assert!(errors.contains("error: character 'é' has no code in PETSCII"), "{}", errors);
+}
@@ -0,0 +1,81 @@
+//! Runs the assembler binary on source files written to a scratch directory.
+
+// Each test file only uses some of these helpers
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// What the assembler printed and whether it succeeded
+pub struct Run {
+    pub success: bool,
+    /// The exit code, which is 1 whenever assembly fails
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Run {
+    /// The hex output with whitespace normalised, failing if assembly failed
+    pub fn bytes(&self) -> String {
+        assert!(self.success, "assembly failed:\n{}", self.stderr);
+        self.stdout.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    /// The rendered errors, failing if assembly succeeded
+    pub fn errors(&self) -> &str {
+        assert!(!self.success, "assembly succeeded:\n{}", self.stdout);
+        &self.stderr
+    }
+}
+
+/// Creates an empty directory that no other test uses
+pub fn scratch() -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"))
+        .join(format!("asm-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::SeqCst)));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes each `(path, contents)` pair into a scratch directory and assembles
+/// the first one, passing `args` before the input file
+pub fn run_files(files: &[(&str, &[u8])], args: &[&str]) -> Run {
+    let dir = scratch();
+    for (path, contents) in files {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+    let mut args = args.to_vec();
+    args.push(files[0].0);
+    run_in(&dir, &args)
+}
+
+/// Runs the assembler in a directory with the given arguments
+pub fn run_in(dir: &Path, args: &[&str]) -> Run {
+    let output = Command::new(env!("CARGO_BIN_EXE_assembler6502"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap();
+    Run {
+        success: output.status.success(),
+        code: output.status.code(),
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+    }
+}
+
+/// Assembles a single file
+pub fn run(code: &str) -> Run {
+    run_files(&[("prog.asm", code.as_bytes())], &[])
+}
+
+/// Assembles a single file, returning the hex output
+pub fn assemble(code: &str) -> String {
+    run(code).bytes()
+}
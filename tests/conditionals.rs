@@ -0,0 +1,120 @@
+mod common;
+
+use common::{assemble, run};
+
+fn revision(n: u8) -> String {
+    let code = format!("\
+REVISION = {}
+.IF REVISION >= 2
+LDA #$40
+.ELSEIF REVISION == 1
+LDA #$20
+.ELSE
+LDA #$80
+.ENDIF
+", n);
+    assemble(&code)
+}
+
+#[test]
+fn the_first_true_branch_is_assembled() {
+    assert_eq!(revision(3), "A9 40");
+    assert_eq!(revision(1), "A9 20");
+    assert_eq!(revision(0), "A9 80");
+}
+
+#[test]
+fn blocks_nest() {
+    let code = "\
+A1 = 1
+B1 = 0
+.IF A1
+.IF B1
+.BYTE 1
+.ELSE
+.BYTE 2
+.ENDIF
+.ELSE
+.IF 1
+.BYTE 3
+.ENDIF
+.ENDIF
+";
+    assert_eq!(assemble(code), "02");
+}
+
+#[test]
+fn ifdef_checks_for_declared_symbols() {
+    let code = "\
+DEBUG = 0
+START: NOP
+.IFDEF DEBUG
+.BYTE 1
+.ENDIF
+.IFDEF START
+.BYTE 2
+.ENDIF
+.IFNDEF RELEASE
+.BYTE 3
+.ENDIF
+.IFDEF RELEASE
+.BYTE 4
+.ENDIF
+";
+    assert_eq!(assemble(code), "EA 01 02 03");
+}
+
+#[test]
+fn labels_in_skipped_branches_are_not_declared() {
+    let code = "\
+.IF 0
+SKIPPED: NOP
+.ENDIF
+.IFNDEF SKIPPED
+.BYTE 1
+.ENDIF
+";
+    assert_eq!(assemble(code), "01");
+}
+
+#[test]
+fn unterminated_blocks_are_reported() {
+    let errors = run("NOP\n.IF 1\nNOP").errors().to_string();
+    assert!(errors.contains("error: `.IF` is never closed\n --> prog.asm:2:1"), "{}", errors);
+    assert!(errors.contains("add `.ENDIF` where the block should end"), "{}", errors);
+}
+
+#[test]
+fn unmatched_directives_are_reported() {
+    assert!(run(".ENDIF").errors().contains("error: `.ENDIF` without a matching `.IF`"));
+    assert!(run(".ELSE").errors().contains("error: `.ELSE` without a matching `.IF`"));
+    assert!(run(".ELSEIF 1").errors().contains("error: `.ELSEIF` without a matching `.IF`"));
+}
+
+#[test]
+fn conditions_can_only_use_defines_above_them() {
+    let errors = run(".IF LATER\n.ENDIF\nLATER = 1").errors().to_string();
+    assert!(errors.contains("error: undefined symbol `LATER`"), "{}", errors);
+}
+
+#[test]
+fn lines_in_branches_that_are_not_assembled_are_not_checked() {
+    let code = "\
+.IF 0
+LDZ #1
+.BYTE 1,
+UNDEFINED_MACRO 1, 2
+.ELSE
+NOP
+.ENDIF
+";
+    assert_eq!(assemble(code), "EA");
+}
+
+#[test]
+fn block_directives_with_errors_stay_matched() {
+    let errors = run(".IF 1 2\nNOP\n.ENDIF\n.ENDIF").errors().to_string();
+    assert!(errors.contains("error: expected end of line but found '2'\n --> prog.asm:1:7"), "{}", errors);
+    assert!(errors.contains("error: `.ENDIF` without a matching `.IF`\n --> prog.asm:4:1"), "{}", errors);
+    assert_eq!(errors.matches("error:").count(), 2, "{}", errors);
+}
@@ -0,0 +1,127 @@
+mod common;
+
+use common::{assemble, run};
+
+#[test]
+fn bytes_take_lists_of_values() {
+    assert_eq!(assemble(".BYTE 1, $FF, %10, 2*3"), "01 FF 02 06");
+    assert_eq!(assemble(".DB 7"), "07");
+}
+
+#[test]
+fn bytes_take_the_halves_of_labels() {
+    let code = "\
+*= $1234
+TABLE: .BYTE <TABLE, >TABLE
+";
+    assert_eq!(assemble(code), "* = 34 12 34 12");
+}
+
+#[test]
+fn bytes_take_strings() {
+    assert_eq!(assemble(".BYTE \"HI\", 0"), "48 49 00");
+}
+
+#[test]
+fn labels_after_data_account_for_its_size() {
+    let code = "\
+*= $1000
+JMP CODE
+DATA: .BYTE 1, 2, 3
+CODE: LDA DATA
+";
+    assert_eq!(assemble(code), "* = 00 10 4C 06 10 01 02 03 AD 03 10");
+}
+
+#[test]
+fn bytes_over_255_are_reported() {
+    let errors = run(".BYTE 1, 256").errors().to_string();
+    assert!(errors.contains("error: value 256 does not fit in a byte"), "{}", errors);
+    assert!(run(".BYTE -129").errors().contains("error: value -129 does not fit in a byte"));
+    assert_eq!(assemble(".BYTE -1"), "FF");
+}
+
+#[test]
+fn arithmetic_overflow_is_reported() {
+    let errors = run(".WORD (1 << 63) * 2").errors().to_string();
+    assert!(errors.contains("error: arithmetic overflow\n --> prog.asm:1:7"), "{}", errors);
+    assert!(run(".WORD (1 << 63) / -1").errors().contains("error: arithmetic overflow"));
+    assert!(run(".WORD 1 / 0").errors().contains("error: division by zero"));
+}
+
+#[test]
+fn bytes_need_a_value() {
+    assert!(run(".BYTE").errors().contains("error: expected a value"));
+    assert!(run(".BYTE 1,").errors().contains("error: expected a value"));
+}
+
+#[test]
+fn words_are_little_endian() {
+    assert_eq!(assemble(".WORD $1234, 1"), "34 12 01 00");
+    assert_eq!(assemble(".DW $ABCD"), "CD AB");
+}
+
+#[test]
+fn dbyt_is_big_endian() {
+    assert_eq!(assemble(".DBYT $1234, 1"), "12 34 00 01");
+}
+
+#[test]
+fn words_take_forward_references() {
+    let code = "\
+*= $1000
+VECTORS: .WORD START, END
+START: NOP
+END: RTS
+";
+    assert_eq!(assemble(code), "* = 00 10 04 10 05 10 EA 60");
+}
+
+#[test]
+fn words_over_65535_are_reported() {
+    let errors = run(".WORD $10000").errors().to_string();
+    assert!(errors.contains("error: value 65536 does not fit in 2 bytes"), "{}", errors);
+    assert!(run(".DBYT -32769").errors().contains("error: value -32769 does not fit in 2 bytes"));
+    assert_eq!(assemble(".WORD -1"), "FF FF");
+}
+
+#[test]
+fn text_keeps_its_case() {
+    assert_eq!(assemble(".TEXT \"Hi!\""), "48 69 21");
+    assert_eq!(assemble(".ascii \"ok\""), "6F 6B");
+}
+
+#[test]
+fn asciiz_ends_with_a_zero() {
+    assert_eq!(assemble(".ASCIIZ \"Hi\""), "48 69 00");
+    assert_eq!(assemble(".ASCIIZ \"A\", \"B\""), "41 42 00");
+}
+
+#[test]
+fn text_escapes() {
+    assert_eq!(assemble(r#".TEXT "a\n\r\t\0\\\"""#), "61 0A 0D 09 00 5C 22");
+    assert_eq!(assemble(r#".TEXT "\x41\x7F""#), "41 7F");
+}
+
+#[test]
+fn bad_escapes_are_reported() {
+    let errors = run(r#".TEXT "\q""#).errors().to_string();
+    assert!(errors.contains("error: unknown escape sequence `\\q`"), "{}", errors);
+    assert!(errors.contains("1 | .TEXT \"\\q\"\n  |        ^^"), "{}", errors);
+    assert!(run(r#".TEXT "ab"#).errors().contains("error: missing closing quote"));
+}
+
+#[test]
+fn characters_are_numbers() {
+    assert_eq!(assemble("CMP #'A'"), "C9 41");
+    assert_eq!(assemble("LDA #'0'+5"), "A9 35");
+    assert_eq!(assemble("CMP #'a'"), "C9 61");
+    assert_eq!(assemble(r".BYTE '\n', '\''"), "0A 27");
+    assert_eq!(assemble("NEWLINE = '\\n'\n.BYTE NEWLINE"), "0A");
+}
+
+#[test]
+fn characters_hold_exactly_one_character() {
+    let errors = run("LDA #'ab'").errors().to_string();
+    assert!(errors.contains("error: character literals must contain a single character"), "{}", errors);
+}
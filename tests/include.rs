@@ -0,0 +1,126 @@
+mod common;
+
+use common::run_files;
+
+#[test]
+fn included_files_are_assembled_in_place() {
+    let run = run_files(&[
+        ("prog.asm", b"NOP\n.INCLUDE \"consts.asm\"\nLDA #VALUE\n"),
+        ("consts.asm", b"VALUE = 5\nRTS\n"),
+    ], &[]);
+    assert_eq!(run.bytes(), "EA 60 A9 05");
+}
+
+#[test]
+fn includes_are_found_next_to_the_including_file_first() {
+    let run = run_files(&[
+        ("prog.asm", b".INCLUDE \"src/main.asm\""),
+        ("src/main.asm", b".INCLUDE \"part.asm\""),
+        ("src/part.asm", b".BYTE 1"),
+        ("lib/part.asm", b".BYTE 2"),
+    ], &["-I", "lib"]);
+    assert_eq!(run.bytes(), "01");
+}
+
+#[test]
+fn includes_are_searched_for_in_each_directory_in_order() {
+    let run = run_files(&[
+        ("prog.asm", b".INCLUDE \"part.asm\"\n.INCLUDE \"other.asm\""),
+        ("first/part.asm", b".BYTE 1"),
+        ("second/part.asm", b".BYTE 2"),
+        ("second/other.asm", b".BYTE 3"),
+    ], &["-I", "first", "--include", "second"]);
+    assert_eq!(run.bytes(), "01 03");
+}
+
+#[test]
+fn missing_files_are_reported() {
+    let run = run_files(&[("prog.asm", b".INCLUDE \"missing.asm\"")], &[]);
+    let errors = run.errors();
+    assert!(errors.contains("error: can not find `missing.asm`"), "{}", errors);
+    assert!(errors.contains("add the directory it is in with `-I`"), "{}", errors);
+}
+
+#[test]
+fn errors_point_into_the_included_file() {
+    let run = run_files(&[
+        ("prog.asm", b"NOP\n.INCLUDE \"lib/bad.asm\""),
+        ("lib/bad.asm", b"NOP\nLDZ #1"),
+    ], &[]);
+    let errors = run.errors();
+    assert!(errors.contains("error: unknown opcode `LDZ`\n --> lib/bad.asm:2:1"), "{}", errors);
+}
+
+#[test]
+fn cycles_are_reported() {
+    let run = run_files(&[
+        ("prog.asm", b".INCLUDE \"a.asm\""),
+        ("a.asm", b"NOP\n.INCLUDE \"b.asm\""),
+        ("b.asm", b".INCLUDE \"a.asm\""),
+    ], &[]);
+    let errors = run.errors();
+    assert!(errors.contains("error: `a.asm` ends up including itself\n --> b.asm:1:1"), "{}", errors);
+}
+
+#[test]
+fn files_may_be_included_more_than_once() {
+    let run = run_files(&[
+        ("prog.asm", b".INCLUDE \"byte.asm\"\n.INCLUDE \"byte.asm\""),
+        ("byte.asm", b".BYTE 7"),
+    ], &[]);
+    assert_eq!(run.bytes(), "07 07");
+}
+
+#[test]
+fn incbin_places_the_bytes_of_a_file() {
+    let run = run_files(&[
+        ("prog.asm", b"*= $1000\n.INCBIN \"data.bin\"\nEND: .WORD END"),
+        ("data.bin", &[0, 1, 2, 0xff]),
+    ], &[]);
+    assert_eq!(run.bytes(), "* = 00 10 00 01 02 FF 04 10");
+}
+
+#[test]
+fn incbin_takes_an_offset_and_length() {
+    let files: &[(&str, &[u8])] = &[("prog.asm", b""), ("data.bin", &[0, 1, 2, 3, 4])];
+    let run = |code: &str| {
+        let mut files = files.to_vec();
+        files[0].1 = code.as_bytes();
+        run_files(&files, &[])
+    };
+    assert_eq!(run(".INCBIN \"data.bin\", 2").bytes(), "02 03 04");
+    assert_eq!(run(".INCBIN \"data.bin\", 1, 2").bytes(), "01 02");
+    assert_eq!(run(".INCBIN \"data.bin\", 5").bytes(), "");
+    assert_eq!(run(".INCBIN \"data.bin\", 0, 5").bytes(), "00 01 02 03 04");
+
+    let errors = run(".INCBIN \"data.bin\", 3, 3").errors().to_string();
+    assert!(errors.contains("error: can not read bytes 3 to 6 of `data.bin`, which is 5 bytes long"), "{}", errors);
+    assert!(run(".INCBIN \"data.bin\", 6").errors().contains("error: can not read bytes 6 to 5 of `data.bin`"));
+    assert!(run(".INCBIN \"data.bin\", -1").errors().contains("error: can not read bytes -1 to 5 of `data.bin`"));
+}
+
+#[test]
+fn incbin_uses_the_include_directories() {
+    let run = run_files(&[
+        ("prog.asm", b".INCBIN \"font.bin\""),
+        ("assets/font.bin", &[0x3c, 0x66]),
+    ], &["-I", "assets"]);
+    assert_eq!(run.bytes(), "3C 66");
+    let run = run_files(&[("prog.asm", b".INCBIN \"font.bin\"")], &[]);
+    assert!(run.errors().contains("error: can not find `font.bin`"));
+}
+
+#[test]
+fn empty_files_assemble_to_nothing() {
+    let run = run_files(&[("prog.asm", b"")], &[]);
+    assert_eq!(run.bytes(), "");
+}
+
+#[test]
+fn empty_files_can_be_included() {
+    let run = run_files(&[
+        ("prog.asm", b"NOP\n.INCLUDE \"empty.asm\"\nRTS\n"),
+        ("empty.asm", b""),
+    ], &[]);
+    assert_eq!(run.bytes(), "EA 60");
+}
@@ -0,0 +1,50 @@
+mod common;
+
+use common::{assemble, run};
+
+#[test]
+fn forward_references_to_zero_page_shrink() {
+    let code = "\
+*= $0010
+LDA DATA
+DATA: NOP
+";
+    assert_eq!(assemble(code), "* = 10 00 A5 12 EA");
+}
+
+#[test]
+fn shrinking_one_operand_can_shrink_the_next() {
+    let code = "\
+LDA FIRST
+FIRST: LDA SECOND
+SECOND: LDX THIRD
+THIRD: NOP
+";
+    assert_eq!(assemble(code), "A5 02 A5 04 A6 06 EA");
+}
+
+#[test]
+fn labels_past_zero_page_stay_absolute() {
+    let code = "\
+*= $00FC
+LDA DATA
+NOP
+DATA: NOP
+";
+    assert_eq!(assemble(code), "* = FC 00 AD 00 01 EA EA");
+}
+
+#[test]
+fn backward_references_use_the_final_address() {
+    let code = "\
+*= $1000
+START: NOP
+LDA START,X
+";
+    assert_eq!(assemble(code), "* = 00 10 EA BD 00 10");
+}
+
+#[test]
+fn undefined_labels_are_reported() {
+    assert!(run("LDA MISSING").errors().contains("error: undefined symbol `MISSING`"));
+}
@@ -0,0 +1,64 @@
+mod common;
+
+use std::fs;
+
+use common::{run_in, scratch};
+
+const MAIN: &str = "\
+.IMPORT PRINT
+.EXPORT MSG
+START: JSR PRINT
+MSG: .BYTE 1
+";
+
+const PRINT: &str = "\
+.IMPORT MSG
+.EXPORT PRINT
+PRINT: LDA MSG
+RTS
+";
+
+#[test]
+fn objects_are_linked_by_the_layout() {
+    let dir = scratch();
+    fs::write(dir.join("main.asm"), MAIN).unwrap();
+    fs::write(dir.join("print.asm"), PRINT).unwrap();
+    fs::write(dir.join("layout.cfg"), "CODE start=$8000 size=12 fill=$EA").unwrap();
+
+    assert!(run_in(&dir, &["--object", "main.asm", "-o", "main.o"]).success);
+    assert!(run_in(&dir, &["--object", "print.asm", "-o", "print.o"]).success);
+    let run = run_in(&dir, &["link", "main.o", "print.o", "--layout", "layout.cfg"]);
+    assert_eq!(run.bytes(), "* = 00 80 20 04 80 01 AD 03 80 60 EA EA EA EA");
+}
+
+#[test]
+fn missing_imports_are_reported_when_linking() {
+    let dir = scratch();
+    fs::write(dir.join("main.asm"), MAIN).unwrap();
+    fs::write(dir.join("layout.cfg"), "CODE start=$8000 size=12").unwrap();
+
+    assert!(run_in(&dir, &["--object", "main.asm", "-o", "main.o"]).success);
+    let run = run_in(&dir, &["link", "main.o", "--layout", "layout.cfg"]);
+    assert!(run.errors().contains("error: undefined symbol `PRINT`\n --> main.o:5:1"), "{}", run.stderr);
+}
+
+#[test]
+fn object_files_can_not_set_the_address() {
+    let dir = scratch();
+    fs::write(dir.join("org.asm"), "*= $1000\nNOP").unwrap();
+    let run = run_in(&dir, &["--object", "org.asm", "-o", "org.o"]);
+    assert!(run.errors().contains("error: `ORG` can not be used in an object file, where addresses are not known yet"));
+}
+
+#[test]
+fn exported_defines_use_the_address_of_their_own_line() {
+    let dir = scratch();
+    fs::write(dir.join("here.asm"), "NOP\nHERE = *\n.EXPORT HERE\nRTS").unwrap();
+    fs::write(dir.join("jump.asm"), ".IMPORT HERE\nJMP HERE").unwrap();
+    fs::write(dir.join("layout.cfg"), "CODE start=$2000 size=5").unwrap();
+
+    assert!(run_in(&dir, &["--object", "here.asm", "-o", "here.o"]).success);
+    assert!(run_in(&dir, &["--object", "jump.asm", "-o", "jump.o"]).success);
+    let run = run_in(&dir, &["link", "here.o", "jump.o", "--layout", "layout.cfg"]);
+    assert_eq!(run.bytes(), "* = 00 20 EA 60 4C 01 20");
+}
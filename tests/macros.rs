@@ -0,0 +1,81 @@
+mod common;
+
+use common::{assemble, run};
+
+const INC16: &str = "\
+.MACRO INC16 addr
+INC addr
+BNE done
+INC addr+1
+done:
+.ENDMACRO
+";
+
+#[test]
+fn macros_assemble_their_lines_in_place() {
+    let code = format!("{}*= $1000\nINC16 $20\nNOP", INC16);
+    assert_eq!(assemble(&code), "* = 00 10 E6 20 D0 02 E6 21 EA");
+}
+
+#[test]
+fn local_labels_are_separate_for_each_use() {
+    let code = format!("{}*= $1000\nINC16 $20\nINC16 $1234\nRTS", INC16);
+    assert_eq!(assemble(&code), "* = 00 10 E6 20 D0 02 E6 21 EE 34 12 D0 03 EE 35 12 60");
+}
+
+#[test]
+fn arguments_can_be_given_by_name() {
+    let code = "\
+.MACRO COPY from, to
+LDA from
+STA to
+.ENDM
+COPY to=$20, from=$10
+COPY $30, to=$40
+";
+    assert_eq!(assemble(code), "A5 10 85 20 A5 30 85 40");
+}
+
+#[test]
+fn macros_can_use_other_macros() {
+    let code = format!("{}.MACRO INC32 addr\nINC16 addr\nINC16 addr+2\n.ENDM\nINC32 $10", INC16);
+    assert_eq!(assemble(&code), "E6 10 D0 02 E6 11 E6 12 D0 02 E6 13");
+}
+
+#[test]
+fn errors_point_at_the_macro_line_and_its_use() {
+    let code = "\
+.MACRO STORE value
+STA #value
+.ENDM
+NOP
+STORE 1
+";
+    let errors = run(code).errors().to_string();
+    assert!(errors.contains("error: `STA` does not support immediate addressing\n --> prog.asm:2:5"), "{}", errors);
+    assert!(errors.contains("note: in this use of macro `STORE`\n --> prog.asm:5:1"), "{}", errors);
+}
+
+#[test]
+fn wrong_arguments_are_reported() {
+    let code = format!("{}INC16\nINC16 1, 2\nINC16 ptr=1", INC16);
+    let errors = run(&code).errors().to_string();
+    assert!(errors.contains("error: missing an argument for `ADDR`"), "{}", errors);
+    assert!(errors.contains("error: macro `INC16` takes 1 argument but 2 were given"), "{}", errors);
+    assert!(errors.contains("error: macro `INC16` has no parameter `PTR`"), "{}", errors);
+    assert!(errors.contains("note: the macro is defined here\n --> prog.asm:1:1"), "{}", errors);
+    assert_eq!(errors.matches("note: the macro is defined here").count(), 3, "{}", errors);
+}
+
+#[test]
+fn recursion_is_reported() {
+    let code = ".MACRO LOOP\nLOOP\n.ENDM\nLOOP";
+    assert!(run(code).errors().contains("error: macro `LOOP` ends up using itself"));
+}
+
+#[test]
+fn unterminated_macros_are_reported() {
+    let errors = run(".MACRO OPEN\nNOP").errors().to_string();
+    assert!(errors.contains("error: `.MACRO` is never closed"), "{}", errors);
+    assert!(run(".ENDMACRO").errors().contains("error: `.ENDMACRO` without a matching `.MACRO`"));
+}
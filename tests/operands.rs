@@ -0,0 +1,103 @@
+mod common;
+
+use common::{assemble, run};
+
+#[test]
+fn absolute_operands_are_little_endian() {
+    let code = "\
+*= $1234
+START: LDA START
+STA START,X
+LDA START,Y
+JMP (START)
+JSR START
+JMP START
+";
+    assert_eq!(assemble(code), "* = 34 12 AD 34 12 9D 34 12 B9 34 12 6C 34 12 20 34 12 4C 34 12");
+}
+
+#[test]
+fn addresses_from_ff_up_keep_their_high_byte() {
+    assert_eq!(assemble("JMP $00FF"), "4C FF 00");
+    assert_eq!(assemble("JMP $0100"), "4C 00 01");
+    assert_eq!(assemble("LDA $FFFF"), "AD FF FF");
+}
+
+#[test]
+fn zero_page_and_indirect_operands_take_one_byte() {
+    assert_eq!(assemble("LDA $10"), "A5 10");
+    assert_eq!(assemble("LDA $10,X"), "B5 10");
+    assert_eq!(assemble("LDX $10,Y"), "B6 10");
+    assert_eq!(assemble("LDA ($10,X)"), "A1 10");
+    assert_eq!(assemble("LDA ($10),Y"), "B1 10");
+    assert_eq!(assemble("LDA #$10"), "A9 10");
+}
+
+#[test]
+fn zero_page_falls_back_to_absolute_when_the_opcode_has_no_zero_page_mode() {
+    assert_eq!(assemble("LDA $10,Y"), "B9 10 00");
+    assert_eq!(assemble("JMP $10"), "4C 10 00");
+}
+
+#[test]
+fn values_that_do_not_fit_are_reported() {
+    let errors = run("LDA #$100").errors().to_string();
+    assert!(errors.contains("error: value 256 does not fit in a byte"), "{}", errors);
+    assert!(errors.contains("use `<` or `>` to take the low or high byte"), "{}", errors);
+    assert_eq!(assemble("LDA #<$1234\nLDX #>$1234"), "A9 34 A2 12");
+}
+
+#[test]
+fn unsupported_addressing_modes_are_reported() {
+    let errors = run("STA #1").errors().to_string();
+    assert!(errors.contains("error: `STA` does not support immediate addressing"), "{}", errors);
+    assert!(errors.contains("`STA` supports: absolute, absolute,X, absolute,Y, zero page"), "{}", errors);
+}
+
+#[test]
+fn missing_operands_are_reported() {
+    let errors = run("BNE").errors().to_string();
+    assert!(errors.contains("error: `BNE` needs an operand\n --> prog.asm:1:1"), "{}", errors);
+    assert!(errors.contains("`BNE` supports: relative"), "{}", errors);
+    assert!(run("LDA ; comment").errors().contains("error: `LDA` needs an operand"));
+    assert_eq!(assemble("ASL\nNOP ; comment"), "0A EA");
+}
+
+#[test]
+fn size_suffixes_force_the_operand_size() {
+    assert_eq!(assemble("LDA.W $0010"), "AD 10 00");
+    assert_eq!(assemble("LDA.W $10,X"), "BD 10 00");
+    assert_eq!(assemble("LDA.B $0010"), "A5 10");
+    assert_eq!(assemble("lda.b $0010"), "A5 10");
+    let code = "\
+*= $1000
+LDA.B DATA
+NOP
+DATA = $20
+";
+    assert_eq!(assemble(code), "* = 00 10 A5 20 EA");
+}
+
+#[test]
+fn forward_references_can_be_forced_to_zero_page() {
+    let code = "\
+LDA.B FWD
+FWD: NOP
+";
+    assert_eq!(assemble(code), "A5 02 EA");
+}
+
+#[test]
+fn sizes_the_opcode_can_not_use_are_reported() {
+    let errors = run("JMP.B $10").errors().to_string();
+    assert!(errors.contains("error: can not force a 1 byte operand for `JMP` with absolute addressing"), "{}", errors);
+    assert!(run("LDX.B $10,X").errors().contains("error: can not force a 1 byte operand for `LDX` with absolute,X addressing"));
+    assert!(run("LDA.B $1234").errors().contains("error: value 4660 does not fit in a byte"));
+}
+
+#[test]
+fn indirect_indexed_by_x_is_rejected() {
+    let errors = run("LDA ($10),X").errors().to_string();
+    assert!(errors.contains("error: `(addr),X` is not an addressing mode, use `(addr,X)`\n --> prog.asm:1:5"), "{}", errors);
+    assert_eq!(assemble("LDA ($10)+1,X"), "B5 11");
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use common::{assemble, run};
+
+#[test]
+fn blocks_are_repeated() {
+    assert_eq!(assemble(".REPEAT 3\nNOP\n.ENDREP\nRTS"), "EA EA EA 60");
+    assert_eq!(assemble(".REPEAT 0\nNOP\n.ENDREP\nRTS"), "60");
+}
+
+#[test]
+fn the_counter_starts_at_zero() {
+    assert_eq!(assemble(".REPEAT 5, I\n.BYTE I*I\n.ENDREP"), "00 01 04 09 10");
+}
+
+#[test]
+fn blocks_nest() {
+    let code = "\
+.REPEAT 2, Y1
+.REPEAT 3, X1
+.BYTE Y1*16 + X1
+.ENDREP
+.ENDREP
+";
+    assert_eq!(assemble(code), "00 01 02 10 11 12");
+}
+
+#[test]
+fn labels_are_local_to_each_iteration() {
+    let code = "\
+*= $1000
+.REPEAT 2
+LOOP: DEX
+BNE LOOP
+.ENDREP
+";
+    assert_eq!(assemble(code), "* = 00 10 CA D0 FD CA D0 FD");
+}
+
+#[test]
+fn counts_can_use_defines_above_them() {
+    assert_eq!(assemble("N = 2\n.REPEAT N*2\n.BYTE 1\n.ENDREP"), "01 01 01 01");
+    assert!(run(".REPEAT N\n.ENDREP\nN = 1").errors().contains("error: undefined symbol `N`"));
+}
+
+#[test]
+fn errors_point_at_the_iteration() {
+    let errors = run(".REPEAT 3, I\n.BYTE 254 + I\n.ENDREP").errors().to_string();
+    assert!(errors.contains("error: value 256 does not fit in a byte\n --> prog.asm:2:7"), "{}", errors);
+    assert!(errors.contains("note: in this `.REPEAT` with the counter at 2\n --> prog.asm:1:1"), "{}", errors);
+}
+
+#[test]
+fn bad_counts_are_reported() {
+    assert!(run(".REPEAT -1\n.ENDREP").errors().contains("error: can not repeat -1 times"));
+    let errors = run(".REPEAT 2\nNOP").errors().to_string();
+    assert!(errors.contains("error: `.REPEAT` is never closed"), "{}", errors);
+    assert!(run(".ENDREP").errors().contains("error: `.ENDREP` without a matching `.REPEAT`"));
+}
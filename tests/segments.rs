@@ -0,0 +1,103 @@
+mod common;
+
+use common::run_files;
+
+const LAYOUT: &str = "\
+ZEROPAGE start=$00   size=$100 output=no
+CODE     start=$8000 size=$10
+DATA     start=$9000 size=4    fill=$FF
+";
+
+fn run(code: &str) -> common::Run {
+    run_files(&[("prog.asm", code.as_bytes()), ("layout.cfg", LAYOUT.as_bytes())], &["--layout", "layout.cfg"])
+}
+
+#[test]
+fn segments_are_placed_in_their_areas() {
+    let code = "\
+.SEGMENT \"ZEROPAGE\"
+PTR: .RES 2
+.SEGMENT \"DATA\"
+TABLE: .BYTE 1
+.SEGMENT \"CODE\"
+LDA TABLE
+STA PTR
+";
+    assert_eq!(run(code).bytes(), "* = 00 80 AD 00 90 85 00 * = 00 90 01 FF FF FF");
+}
+
+#[test]
+fn segments_continue_where_they_were_left() {
+    let code = "\
+.SEGMENT \"CODE\"
+NOP
+.SEGMENT \"DATA\"
+.BYTE 1
+.SEGMENT \"CODE\"
+RTS
+.SEGMENT \"DATA\"
+.BYTE 2
+";
+    assert_eq!(run(code).bytes(), "* = 00 80 EA 60 * = 00 90 01 02 FF FF");
+}
+
+#[test]
+fn segments_that_are_not_output_only_reserve_space() {
+    let code = "\
+.SEGMENT \"ZEROPAGE\"
+A1: .RES 1
+B1: .RES 2
+.SEGMENT \"CODE\"
+.BYTE A1, B1
+";
+    assert_eq!(run(code).bytes(), "* = 00 80 00 01 * = 00 90 FF FF FF FF");
+
+    let errors = run(".SEGMENT \"ZEROPAGE\"\nNOP").errors().to_string();
+    assert!(errors.contains("error: segment `ZEROPAGE` is not output so it can only reserve space"), "{}", errors);
+}
+
+#[test]
+fn overflowing_an_area_is_reported() {
+    let errors = run(".SEGMENT \"DATA\"\n.BYTE 1, 2, 3\n.BYTE 4, 5, 6").errors().to_string();
+    assert!(errors.contains("error: segment `DATA` overflows its area by 2 bytes\n --> prog.asm:3:1"), "{}", errors);
+    let errors = run(".SEGMENT \"DATA\"\n.RES 5").errors().to_string();
+    assert!(errors.contains("error: segment `DATA` overflows its area by 1 byte\n"), "{}", errors);
+}
+
+#[test]
+fn unknown_segments_are_reported() {
+    let errors = run(".SEGMENT \"BSS\"").errors().to_string();
+    assert!(errors.contains("error: unknown segment `BSS`"), "{}", errors);
+    assert!(errors.contains("ZEROPAGE, CODE, DATA"), "{}", errors);
+}
+
+#[test]
+fn layout_errors_point_into_the_layout_file() {
+    let run = run_files(&[("prog.asm", b"NOP"), ("layout.cfg", b"CODE start=$8000")], &["--layout", "layout.cfg"]);
+    let errors = run.errors();
+    assert!(errors.contains("error: segment `CODE` needs a `start` and a `size`\n --> layout.cfg:1:1"), "{}", errors);
+}
+
+#[test]
+fn lines_before_the_first_segment_go_in_code() {
+    assert_eq!(run("NOP\n.SEGMENT \"DATA\"\n.BYTE 1").bytes(), "* = 00 80 EA * = 00 90 01 FF FF FF");
+}
+
+#[test]
+fn lines_before_the_first_segment_need_a_code_segment() {
+    let layout = "ZEROPAGE start=0 size=$100 output=no\nROM start=$8000 size=2";
+    let run = |code: &str| {
+        run_files(&[("prog.asm", code.as_bytes()), ("layout.cfg", layout.as_bytes())], &["--layout", "layout.cfg"])
+    };
+    let errors = run("PTR = 2\nNOP").errors().to_string();
+    assert!(errors.contains("error: code before the first `.SEGMENT` with no `CODE` segment to go in\n --> prog.asm:2:1"), "{}", errors);
+    assert!(errors.contains("add a `.SEGMENT` above it, or a `CODE` segment to the layout"), "{}", errors);
+    assert_eq!(run(".SEGMENT \"ROM\"\nNOP").bytes(), "* = 00 80 EA");
+}
+
+#[test]
+fn origins_can_not_be_used_with_a_layout() {
+    let errors = run("NOP\n*= $0000\nNOP").errors().to_string();
+    assert!(errors.contains("error: `ORG` can not be used with a layout, which places each segment in its area\n --> prog.asm:2:1"), "{}", errors);
+    assert!(errors.contains("add a segment to the layout for the code and switch to it with `.SEGMENT`"), "{}", errors);
+}
@@ -0,0 +1,106 @@
+mod common;
+
+use common::{assemble, run};
+
+#[test]
+fn reserved_space_is_zero_filled() {
+    assert_eq!(assemble(".RES 3\nNOP"), "00 00 00 EA");
+    assert_eq!(assemble(".DS 2"), "00 00");
+}
+
+#[test]
+fn fill_takes_a_value() {
+    assert_eq!(assemble(".FILL 4, $EA"), "EA EA EA EA");
+    assert_eq!(assemble(".RES 2, 'x'"), "78 78");
+}
+
+#[test]
+fn labels_after_reserved_space_move_past_it() {
+    let code = "\
+*= $1000
+BUFFER: .RES 256
+END: .WORD BUFFER, END
+";
+    assert!(assemble(code).ends_with("00 00 10 00 11"));
+}
+
+#[test]
+fn sizes_can_use_earlier_labels_and_the_pc() {
+    let code = "\
+*= $1000
+START: NOP
+.RES $1004 - *
+.RES 5 - (* - START), $FF
+END: NOP
+";
+    assert_eq!(assemble(code), "* = 00 10 EA 00 00 00 FF EA");
+}
+
+#[test]
+fn fill_values_over_255_are_reported() {
+    assert!(run(".FILL 2, 256").errors().contains("error: value 256 does not fit in a byte"));
+}
+
+#[test]
+fn negative_sizes_are_reported() {
+    assert!(run(".RES -1").errors().contains("error: can not skip -1 bytes"));
+}
+
+#[test]
+fn sizes_running_past_the_end_of_memory_are_reported() {
+    let errors = run("*= $FFF0\n.RES $20").errors().to_string();
+    assert!(errors.contains("error: skipping 32 bytes from $FFF0 runs past the end of memory"), "{}", errors);
+    assert!(assemble("*= $FFF0\n.RES $10").ends_with("00 00"));
+}
+
+#[test]
+fn align_pads_to_the_next_boundary() {
+    assert_eq!(assemble("*= $1001\n.ALIGN 4\nNOP"), "* = 01 10 00 00 00 EA");
+    assert_eq!(assemble("*= $1001\n.ALIGN 4, $FF\nNOP"), "* = 01 10 FF FF FF EA");
+}
+
+#[test]
+fn align_does_nothing_on_a_boundary() {
+    assert_eq!(assemble("*= $1000\n.ALIGN 256\nNOP"), "* = 00 10 EA");
+}
+
+#[test]
+fn align_moves_with_the_code_before_it() {
+    let code = "\
+*= $10FC
+LDA TABLE
+.ALIGN 256
+TABLE: .BYTE 1
+";
+    assert_eq!(assemble(code), "* = FC 10 AD 00 11 00 01");
+}
+
+#[test]
+fn bad_boundaries_are_reported() {
+    assert!(run(".ALIGN 0").errors().contains("error: can not align to 0 bytes"));
+    assert!(run(".ALIGN $10001").errors().contains("error: can not align to 65537 bytes"));
+}
+
+#[test]
+fn sizes_that_never_settle_are_reported() {
+    let errors = run(".RES 1 - (END & 1)\nEND: NOP").errors().to_string();
+    assert!(errors.contains("error: layout does not settle, the size of this `.RES` keeps changing\n --> prog.asm:1:6"), "{}", errors);
+    assert!(errors.contains("make the size depend only on labels placed before it"), "{}", errors);
+    assert!(run("*= 1\n.ALIGN 3 - (END & 1)\nEND: NOP").errors().contains("the size of this `.ALIGN` keeps changing"));
+}
+
+#[test]
+fn origins_that_never_settle_are_reported() {
+    let errors = run("*= $11 - (X & 1)\nX: NOP").errors().to_string();
+    assert!(errors.contains("error: layout does not settle, the address this `ORG` moves to keeps changing\n --> prog.asm:1:1"), "{}", errors);
+    assert!(errors.contains("make the address depend only on labels placed before it"), "{}", errors);
+}
+
+#[test]
+fn code_past_the_end_of_memory_is_reported() {
+    let errors = run("*= $FFFE\nJMP 0").errors().to_string();
+    assert!(errors.contains("error: code or data from $FFFE runs past the end of memory\n --> prog.asm:2:1"), "{}", errors);
+    let errors = run("*= $FFFE\n.WORD 1\nNOP").errors().to_string();
+    assert!(errors.contains("error: code or data from $10000 runs past the end of memory\n --> prog.asm:3:1"), "{}", errors);
+    assert_eq!(assemble("*= $FFFE\n.WORD 1\n*= 0\nNOP"), "* = FE FF 01 00 * = 00 00 EA");
+}
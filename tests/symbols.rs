@@ -0,0 +1,62 @@
+mod common;
+
+use common::{assemble, run, run_files};
+
+fn case_sensitive(code: &str) -> common::Run {
+    run_files(&[("prog.asm", code.as_bytes())], &["--case-sensitive"])
+}
+
+#[test]
+fn keywords_are_case_insensitive() {
+    assert_eq!(assemble("lda #1\nLdX $10,y\nasl a\norg $1000\n.byte 2"), "A9 01 B6 10 0A * = 00 10 02");
+}
+
+#[test]
+fn symbols_are_case_insensitive_by_default() {
+    let code = "\
+value = 1
+loop: LDA #VALUE
+JMP LOOP
+";
+    assert_eq!(assemble(code), "A9 01 4C 00 00");
+    assert!(run("loop: NOP\nLOOP: NOP").errors().contains("error: `LOOP` is already defined"));
+}
+
+#[test]
+fn symbols_differing_in_case_are_distinct_when_case_sensitive() {
+    let code = "\
+value = 1
+VALUE = 2
+loop: LDA #value
+LOOP: LDA #VALUE
+JMP loop
+";
+    assert_eq!(case_sensitive(code).bytes(), "A9 01 A9 02 4C 00 00");
+}
+
+#[test]
+fn case_sensitive_suggestions_ignore_case() {
+    let errors = case_sensitive("Counter = 1\nLDA #counter").errors().to_string();
+    assert!(errors.contains("error: undefined symbol `counter`"), "{}", errors);
+    assert!(errors.contains("did you mean `Counter`?"), "{}", errors);
+}
+
+#[test]
+fn duplicate_symbols_point_at_the_first_definition() {
+    let errors = run("X1 = 1\nNOP\nX1 = 2").errors().to_string();
+    assert!(errors.contains("error: `X1` is already defined\n --> prog.asm:3:1"), "{}", errors);
+    assert!(errors.contains("note: previously defined here\n --> prog.asm:1:1"), "{}", errors);
+}
+
+#[test]
+fn strings_keep_their_case_when_case_sensitive() {
+    assert_eq!(case_sensitive(".text \"aB\"").bytes(), "61 42");
+}
+
+#[test]
+fn the_pc_in_a_define_is_the_address_of_its_own_line() {
+    assert_eq!(assemble("*= $1000\nNOP\nHERE = *\nJMP HERE"), "* = 00 10 EA 4C 01 10");
+    assert_eq!(assemble("*= $1000\nJMP NEXT\nNEXT = * + 1\nNOP"), "* = 00 10 4C 04 10 EA");
+    let run = run_files(&[("prog.asm", b"*= $1000\nNOP\nHERE = *\nJMP HERE")], &["-d"]);
+    assert!(run.stdout.contains("HERE   =   $1001"), "{}", run.stdout);
+}